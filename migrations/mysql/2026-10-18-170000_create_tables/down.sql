@@ -0,0 +1,4 @@
+DROP TABLE `issued_nonce`;
+DROP TABLE `log_leaf`;
+DROP TABLE `block_history`;
+DROP TABLE `block`;
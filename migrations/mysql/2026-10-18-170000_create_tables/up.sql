@@ -0,0 +1,61 @@
+-- The schema of all the SQLite migrations up to add_key_type in one step.
+-- Binary collation keeps the keys case sensitive as in SQLite (uniqueness, prefix ranges)
+CREATE TABLE `block` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT,
+  `public_key` VARCHAR(140) NOT NULL,
+  `data_group` VARCHAR(256) NOT NULL,
+  `data_key` VARCHAR(256) NOT NULL,
+  `data_block` LONGTEXT NOT NULL,
+  `data_version` VARCHAR(32) NOT NULL,
+  `signature` VARCHAR(128) NOT NULL,
+  `secret` VARCHAR(64) NOT NULL,
+  `data_size` INTEGER NOT NULL DEFAULT 0,
+  `data_hash` VARCHAR(64) NOT NULL DEFAULT '',
+  `deleted` BOOLEAN NOT NULL DEFAULT FALSE,
+  `delete_signature` VARCHAR(128) NOT NULL DEFAULT '',
+  `delete_challenge` VARCHAR(64) NOT NULL DEFAULT '',
+  `sig_scheme` VARCHAR(8) NOT NULL DEFAULT 'v1',
+  `key_type` VARCHAR(16) NOT NULL DEFAULT 'secp256k1',
+  UNIQUE(`public_key`, `data_group`, `data_key`)
+) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin;
+
+CREATE TABLE `block_history` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT,
+  `block_id` INTEGER NOT NULL,
+  `public_key` VARCHAR(140) NOT NULL,
+  `data_group` VARCHAR(256) NOT NULL,
+  `data_key` VARCHAR(256) NOT NULL,
+  `data_block` LONGTEXT NOT NULL,
+  `data_version` VARCHAR(32) NOT NULL,
+  `signature` VARCHAR(128) NOT NULL,
+  `data_size` INTEGER NOT NULL,
+  `data_hash` VARCHAR(64) NOT NULL,
+  `action` VARCHAR(16) NOT NULL,
+  `archived_at` BIGINT NOT NULL,
+  `sig_scheme` VARCHAR(8) NOT NULL DEFAULT 'v1',
+  INDEX `block_history_record` (`public_key`, `data_group`, `data_key`)
+) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin;
+
+CREATE TABLE `log_leaf` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT,
+  `leaf_index` BIGINT NOT NULL UNIQUE,
+  `leaf_hash` VARCHAR(64) NOT NULL,
+  `action` VARCHAR(16) NOT NULL,
+  `public_key` VARCHAR(140) NOT NULL,
+  `data_group` VARCHAR(256) NOT NULL,
+  `data_key` VARCHAR(256) NOT NULL,
+  `data_hash` VARCHAR(64) NOT NULL,
+  `data_version` VARCHAR(32) NOT NULL,
+  `signature` VARCHAR(128) NOT NULL,
+  `created_at` BIGINT NOT NULL,
+  INDEX `log_leaf_record` (`public_key`, `data_group`, `data_key`)
+) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin;
+
+CREATE TABLE `issued_nonce` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT,
+  `value` VARCHAR(64) NOT NULL UNIQUE,
+  `created_at` BIGINT NOT NULL,
+  `expires_at` BIGINT NOT NULL,
+  `purpose` VARCHAR(16) NOT NULL DEFAULT 'insert',
+  INDEX `issued_nonce_expires_at` (`expires_at`)
+) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin;
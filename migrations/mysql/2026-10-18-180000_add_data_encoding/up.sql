@@ -0,0 +1,5 @@
+-- Records saved with data_encoding = 'base64' keep raw bytes in data_bytes and an empty data_block
+ALTER TABLE `block` ADD COLUMN `data_encoding` VARCHAR(8) NOT NULL DEFAULT 'utf8';
+ALTER TABLE `block` ADD COLUMN `data_bytes` LONGBLOB;
+ALTER TABLE `block_history` ADD COLUMN `data_encoding` VARCHAR(8) NOT NULL DEFAULT 'utf8';
+ALTER TABLE `block_history` ADD COLUMN `data_bytes` LONGBLOB;
@@ -0,0 +1 @@
+ALTER TABLE `block` DROP COLUMN `previous_secret`;
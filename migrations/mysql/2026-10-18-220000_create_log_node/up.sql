@@ -0,0 +1,9 @@
+-- Hashes of the complete subtrees of the transparency log, filled on append,
+-- so the tree head and the proofs do not need all the leaves
+CREATE TABLE `log_node` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT,
+  `level` INTEGER NOT NULL,
+  `node_index` BIGINT NOT NULL,
+  `node_hash` VARCHAR(64) NOT NULL,
+  UNIQUE (`level`, `node_index`)
+) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin;
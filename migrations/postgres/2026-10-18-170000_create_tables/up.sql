@@ -0,0 +1,62 @@
+-- The schema of all the SQLite migrations up to add_key_type in one step.
+-- Keys are compared byte by byte (COLLATE "C") as in SQLite, so the prefix ranges
+-- and the cursors of the listings do not depend on the locale of the database
+CREATE TABLE block (
+  id SERIAL PRIMARY KEY,
+  public_key VARCHAR(140) NOT NULL,
+  data_group VARCHAR(256) COLLATE "C" NOT NULL,
+  data_key VARCHAR(256) COLLATE "C" NOT NULL,
+  data_block TEXT NOT NULL,
+  data_version VARCHAR(32) NOT NULL,
+  signature VARCHAR(128) NOT NULL,
+  secret VARCHAR(64) NOT NULL,
+  data_size INTEGER NOT NULL DEFAULT 0,
+  data_hash VARCHAR(64) NOT NULL DEFAULT '',
+  deleted BOOLEAN NOT NULL DEFAULT FALSE,
+  delete_signature VARCHAR(128) NOT NULL DEFAULT '',
+  delete_challenge VARCHAR(64) NOT NULL DEFAULT '',
+  sig_scheme VARCHAR(8) NOT NULL DEFAULT 'v1',
+  key_type VARCHAR(16) NOT NULL DEFAULT 'secp256k1',
+  UNIQUE (public_key, data_group, data_key)
+);
+
+CREATE TABLE block_history (
+  id SERIAL PRIMARY KEY,
+  block_id INTEGER NOT NULL,
+  public_key VARCHAR(140) NOT NULL,
+  data_group VARCHAR(256) COLLATE "C" NOT NULL,
+  data_key VARCHAR(256) COLLATE "C" NOT NULL,
+  data_block TEXT NOT NULL,
+  data_version VARCHAR(32) NOT NULL,
+  signature VARCHAR(128) NOT NULL,
+  data_size INTEGER NOT NULL,
+  data_hash VARCHAR(64) NOT NULL,
+  action VARCHAR(16) NOT NULL,
+  archived_at BIGINT NOT NULL,
+  sig_scheme VARCHAR(8) NOT NULL DEFAULT 'v1'
+);
+CREATE INDEX block_history_record ON block_history (public_key, data_group, data_key);
+
+CREATE TABLE log_leaf (
+  id SERIAL PRIMARY KEY,
+  leaf_index BIGINT NOT NULL UNIQUE,
+  leaf_hash VARCHAR(64) NOT NULL,
+  action VARCHAR(16) NOT NULL,
+  public_key VARCHAR(140) NOT NULL,
+  data_group VARCHAR(256) COLLATE "C" NOT NULL,
+  data_key VARCHAR(256) COLLATE "C" NOT NULL,
+  data_hash VARCHAR(64) NOT NULL,
+  data_version VARCHAR(32) NOT NULL,
+  signature VARCHAR(128) NOT NULL,
+  created_at BIGINT NOT NULL
+);
+CREATE INDEX log_leaf_record ON log_leaf (public_key, data_group, data_key);
+
+CREATE TABLE issued_nonce (
+  id SERIAL PRIMARY KEY,
+  value VARCHAR(64) NOT NULL UNIQUE,
+  created_at BIGINT NOT NULL,
+  expires_at BIGINT NOT NULL,
+  purpose VARCHAR(16) NOT NULL DEFAULT 'insert'
+);
+CREATE INDEX issued_nonce_expires_at ON issued_nonce (expires_at);
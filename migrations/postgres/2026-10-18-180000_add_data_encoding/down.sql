@@ -0,0 +1,4 @@
+ALTER TABLE block_history DROP COLUMN data_bytes;
+ALTER TABLE block_history DROP COLUMN data_encoding;
+ALTER TABLE block DROP COLUMN data_bytes;
+ALTER TABLE block DROP COLUMN data_encoding;
@@ -0,0 +1,2 @@
+-- Media type of the block for /raw, it is not covered by the signature
+ALTER TABLE block ADD COLUMN content_type VARCHAR(255);
@@ -0,0 +1,2 @@
+-- The secret consumed by the last update, a signature of it means a concurrent request has won
+ALTER TABLE block ADD COLUMN previous_secret VARCHAR(64) NOT NULL DEFAULT '';
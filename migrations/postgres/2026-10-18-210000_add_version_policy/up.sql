@@ -0,0 +1,2 @@
+-- The policy of data_version a record has been saved with, it is kept on later saves
+ALTER TABLE block ADD COLUMN version_policy VARCHAR(8) NOT NULL DEFAULT 'none';
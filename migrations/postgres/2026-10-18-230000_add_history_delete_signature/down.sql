@@ -0,0 +1,2 @@
+ALTER TABLE block_history DROP COLUMN delete_challenge;
+ALTER TABLE block_history DROP COLUMN delete_signature;
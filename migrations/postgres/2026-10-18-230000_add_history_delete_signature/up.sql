@@ -0,0 +1,3 @@
+-- The signature of a deletion, kept when a tombstone is archived before the record is saved again
+ALTER TABLE block_history ADD COLUMN delete_signature VARCHAR(128) NOT NULL DEFAULT '';
+ALTER TABLE block_history ADD COLUMN delete_challenge VARCHAR(64) NOT NULL DEFAULT '';
@@ -0,0 +1,3 @@
+-- The signature of a deletion is a part of the delete leaf, so the log proves it was authorized
+ALTER TABLE log_leaf ADD COLUMN delete_signature VARCHAR(128) NOT NULL DEFAULT '';
+ALTER TABLE log_leaf ADD COLUMN delete_challenge VARCHAR(64) NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+ALTER TABLE `block` DROP COLUMN `data_hash`;
+ALTER TABLE `block` DROP COLUMN `data_size`;
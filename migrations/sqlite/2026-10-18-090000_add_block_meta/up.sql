@@ -0,0 +1,2 @@
+ALTER TABLE `block` ADD COLUMN `data_size` INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE `block` ADD COLUMN `data_hash` VARCHAR(64) NOT NULL DEFAULT '';
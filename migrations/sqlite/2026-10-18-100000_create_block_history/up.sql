@@ -0,0 +1,15 @@
+CREATE TABLE `block_history` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  `block_id` INTEGER NOT NULL,
+  `public_key` VARCHAR(128) NOT NULL,
+  `data_group` VARCHAR(256) NOT NULL,
+  `data_key` VARCHAR(256) NOT NULL,
+  `data_block` TEXT NOT NULL,
+  `data_version` VARCHAR(32) NOT NULL,
+  `signature` VARCHAR(128) NOT NULL,
+  `data_size` INTEGER NOT NULL,
+  `data_hash` VARCHAR(64) NOT NULL,
+  `action` VARCHAR(16) NOT NULL,
+  `archived_at` BIGINT NOT NULL
+);
+CREATE INDEX `block_history_record` ON `block_history` (`public_key`, `data_group`, `data_key`);
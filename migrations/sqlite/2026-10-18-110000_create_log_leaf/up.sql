@@ -0,0 +1,14 @@
+CREATE TABLE `log_leaf` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  `leaf_index` BIGINT NOT NULL UNIQUE,
+  `leaf_hash` VARCHAR(64) NOT NULL,
+  `action` VARCHAR(16) NOT NULL,
+  `public_key` VARCHAR(128) NOT NULL,
+  `data_group` VARCHAR(256) NOT NULL,
+  `data_key` VARCHAR(256) NOT NULL,
+  `data_hash` VARCHAR(64) NOT NULL,
+  `data_version` VARCHAR(32) NOT NULL,
+  `signature` VARCHAR(128) NOT NULL,
+  `created_at` BIGINT NOT NULL
+);
+CREATE INDEX `log_leaf_record` ON `log_leaf` (`public_key`, `data_group`, `data_key`);
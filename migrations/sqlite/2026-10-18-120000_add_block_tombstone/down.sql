@@ -0,0 +1,2 @@
+ALTER TABLE `block` DROP COLUMN `delete_signature`;
+ALTER TABLE `block` DROP COLUMN `deleted`;
@@ -0,0 +1,2 @@
+ALTER TABLE `block` ADD COLUMN `deleted` BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE `block` ADD COLUMN `delete_signature` VARCHAR(128) NOT NULL DEFAULT '';
@@ -0,0 +1,7 @@
+CREATE TABLE `issued_nonce` (
+  `id` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  `value` VARCHAR(64) NOT NULL UNIQUE,
+  `created_at` BIGINT NOT NULL,
+  `expires_at` BIGINT NOT NULL
+);
+CREATE INDEX `issued_nonce_expires_at` ON `issued_nonce` (`expires_at`);
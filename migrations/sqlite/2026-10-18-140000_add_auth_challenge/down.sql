@@ -0,0 +1,2 @@
+ALTER TABLE `block` DROP COLUMN `delete_challenge`;
+ALTER TABLE `issued_nonce` DROP COLUMN `purpose`;
@@ -0,0 +1,2 @@
+ALTER TABLE `issued_nonce` ADD COLUMN `purpose` VARCHAR(16) NOT NULL DEFAULT 'insert';
+ALTER TABLE `block` ADD COLUMN `delete_challenge` VARCHAR(64) NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+ALTER TABLE `block_history` DROP COLUMN `sig_scheme`;
+ALTER TABLE `block` DROP COLUMN `sig_scheme`;
@@ -0,0 +1,2 @@
+ALTER TABLE `block` ADD COLUMN `sig_scheme` VARCHAR(8) NOT NULL DEFAULT 'v1';
+ALTER TABLE `block_history` ADD COLUMN `sig_scheme` VARCHAR(8) NOT NULL DEFAULT 'v1';
@@ -0,0 +1 @@
+ALTER TABLE `block` DROP COLUMN `key_type`;
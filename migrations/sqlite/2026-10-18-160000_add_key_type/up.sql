@@ -0,0 +1,3 @@
+-- Tagged keys (p256:..., up to 135 characters) exceed VARCHAR(128) of public_key,
+-- SQLite does not enforce the length
+ALTER TABLE `block` ADD COLUMN `key_type` VARCHAR(16) NOT NULL DEFAULT 'secp256k1';
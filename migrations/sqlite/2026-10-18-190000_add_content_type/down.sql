@@ -0,0 +1 @@
+ALTER TABLE `block` DROP COLUMN `content_type`;
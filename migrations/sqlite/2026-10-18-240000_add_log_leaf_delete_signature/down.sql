@@ -0,0 +1,2 @@
+ALTER TABLE `log_leaf` DROP COLUMN `delete_challenge`;
+ALTER TABLE `log_leaf` DROP COLUMN `delete_signature`;
@@ -0,0 +1,6 @@
+-- Backfills of the data written by older versions, the server runs the listed ones
+-- on start and removes them, so the tables are scanned once after the upgrade
+CREATE TABLE `backfill` (
+  `name` VARCHAR(64) NOT NULL PRIMARY KEY
+);
+INSERT INTO `backfill` (`name`) VALUES ('block_meta'), ('log_node');
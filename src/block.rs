@@ -1,16 +1,24 @@
-use bigi::Bigi;
-use bigi_ecc::Point;
 use serde_derive::{Serialize, Deserialize};
 use diesel;
 use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
+use diesel::query_builder::BoxedSelectStatement;
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
 
 use crate::utils::*;
+use crate::db::{DbBackend, DbConnection};
+use crate::crypto::hash_data_block;
+use crate::keys::PublicKey;
+use crate::types::SignatureHex;
+use crate::error::{Error, Result};
+use crate::page::{Page, Order};
+use crate::scheme::SigScheme;
+use crate::encoding::DataEncoding;
+use crate::version::VersionPolicy;
 use crate::schema::block;
 
 
 #[table_name = "block"]
-#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
 pub struct Block {
     pub id: i32,
     pub public_key: String,
@@ -20,73 +28,266 @@ pub struct Block {
     pub data_version: String,
     pub signature: String,
     pub secret: String,
+    pub data_size: i32,
+    pub data_hash: String,
+    pub deleted: bool,
+    pub delete_signature: String,
+    pub delete_challenge: String,
+    pub sig_scheme: String,
+    pub key_type: String,
+    pub data_encoding: String,
+    // Raw bytes of base64 records, data_block is empty in the table for them
+    #[serde(skip)]
+    pub data_bytes: Option<Vec<u8>>,
+    pub content_type: Option<String>,
+    // The secret consumed by the last update
+    #[serde(skip)]
+    pub previous_secret: String,
+    pub version_policy: String,
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct BlockMeta {
+    pub id: i32,
+    pub data_key: String,
+    pub data_version: String,
+    pub signature: String,
+    pub data_size: i32,
+    pub data_hash: String,
+    pub deleted: bool,
+    pub sig_scheme: String,
+    pub data_encoding: String,
+}
+
+
+pub fn stored_data(data_block: &String, data_encoding: DataEncoding) -> Result<(String, Option<Vec<u8>>)> {
+    /* The values of data_block and data_bytes columns, base64 blocks are kept as raw bytes */
+    match data_encoding {
+        DataEncoding::Utf8 => Ok((data_block.clone(), None)),
+        DataEncoding::Base64 => Ok((String::new(), Some(data_encoding.decode(data_block)?))),
+    }
 }
 
 
 impl Block {
-    pub fn check(conn: &SqliteConnection, public_key: &Point) -> bool {
+    pub fn with_data_block(mut self) -> Self {
+        /* Restores base64 data_block of the loaded record from its raw bytes */
+        if let Some(data_bytes) = &self.data_bytes {
+            self.data_block = DataEncoding::Base64.encode(data_bytes);
+        }
+        self
+    }
+
+    pub fn raw_data(&self) -> &[u8] {
+        /* The bytes the signature and data_hash are built from */
+        match &self.data_bytes {
+            Some(data_bytes) => data_bytes,
+            None => self.data_block.as_bytes(),
+        }
+    }
+
+    pub fn compress_public_key(&mut self) {
+        self.public_key = PublicKey::compress_stored_hex(&self.public_key);
+    }
+
+    pub fn check(conn: &DbConnection, public_key: &PublicKey) -> Result<bool> {
         let count: i64 = block::table.filter(
-            block::public_key.eq(hex_from_point(public_key))
-        ).count().get_result(conn).unwrap();
-        count > 0
+            block::public_key.eq(public_key.to_hex())
+        ).filter(block::deleted.eq(false)).count().get_result(conn)?;
+        Ok(count > 0)
+    }
+
+    pub fn groups(conn: &DbConnection, public_key: &PublicKey) -> Result<Vec<String>> {
+        Ok(block::table.filter(
+            block::public_key.eq(public_key.to_hex())
+        ).filter(block::deleted.eq(false)).select(block::data_group).distinct().load(conn)?)
     }
 
-    pub fn groups(conn: &SqliteConnection, public_key: &Point) -> Vec<String> {
-        block::table.filter(
-            block::public_key.eq(hex_from_point(public_key))
-        ).select(block::data_group).distinct().load(conn).unwrap()
+    pub fn keys(conn: &DbConnection, public_key: &PublicKey,
+                data_group: &String, page: &Page) -> Result<(Vec<String>, Option<String>)> {
+        let query = block::table.select((block::id, block::data_key)).into_boxed();
+        let mut records: Vec<(i32, String)> = Self::paginate(
+            query, public_key, data_group, page
+        ).load(conn)?;
+        let next_cursor = Self::next_cursor(&mut records, page, |r| (r.0, &r.1));
+        Ok((records.into_iter().map(|r| r.1).collect(), next_cursor))
     }
 
-    pub fn keys(conn: &SqliteConnection, public_key: &Point,
-                data_group: &String) -> Vec<String> {
-        block::table.filter(block::public_key.eq(hex_from_point(public_key)))
-                    .filter(block::data_group.eq(data_group))
-                    .select(block::data_key).load(conn).unwrap()
+    pub fn list(conn: &DbConnection, public_key: &PublicKey,
+                data_group: &String, page: &Page) -> Result<(Vec<Self>, Option<String>)> {
+        let query = block::table.into_boxed();
+        let mut records: Vec<Self> = Self::paginate(
+            query, public_key, data_group, page
+        ).load(conn)?;
+        let next_cursor = Self::next_cursor(&mut records, page, |r| (r.id, &r.data_key));
+        Ok((records.into_iter().map(Self::with_data_block).collect(), next_cursor))
     }
 
-    pub fn list(conn: &SqliteConnection, public_key: &Point,
-                data_group: &String) -> Vec<Self> {
-        block::table.filter(block::public_key.eq(hex_from_point(public_key)))
-                    .filter(block::data_group.eq(data_group))
-                    .load(conn).unwrap()
+    pub fn meta(conn: &DbConnection, public_key: &PublicKey,
+                data_group: &String, page: &Page) -> Result<(Vec<BlockMeta>, Option<String>)> {
+        let query = block::table.select((
+            block::id, block::data_key, block::data_version, block::signature,
+            block::data_size, block::data_hash, block::deleted, block::sig_scheme,
+            block::data_encoding,
+        )).into_boxed();
+        let mut records: Vec<BlockMeta> = Self::paginate(
+            query, public_key, data_group, page
+        ).load(conn)?;
+        let next_cursor = Self::next_cursor(&mut records, page, |r| (r.id, &r.data_key));
+        Ok((records, next_cursor))
     }
 
-    pub fn get(conn: &SqliteConnection, public_key: &Point,
-               data_group: &String, data_key: &String) -> Option<Self> {
-        match block::table.filter(block::public_key.eq(hex_from_point(public_key)))
-                          .filter(block::data_group.eq(data_group))
-                          .filter(block::data_key.eq(data_key))
-                          .first(conn) {
-            Ok(block) => Some(block),
-            Err(_) => None
+    pub fn fill_meta(conn: &DbConnection) -> Result<usize> {
+        /* Calculates data_size and data_hash for the records created before
+           these columns were added, the blocks are loaded one by one */
+        let ids: Vec<i32> = block::table.filter(block::data_hash.eq(""))
+                                        .filter(block::deleted.eq(false))
+                                        .select(block::id).load(conn)?;
+        for id in ids.iter() {
+            let data_block: String = block::table.filter(block::id.eq(id))
+                                                 .select(block::data_block)
+                                                 .first(conn)?;
+            diesel::update(block::table.filter(block::id.eq(id))).set((
+                block::data_size.eq(data_block.len() as i32),
+                block::data_hash.eq(hex_from_bytes(&hash_data_block(data_block.as_bytes()))),
+            )).execute(conn)?;
         }
+        Ok(ids.len())
     }
 
-    pub fn insert(conn: &SqliteConnection, public_key: &Point, data_group: &String,
-                  data_key: &String, data_block: &String, data_version: &String,
-                  signature: &(Bigi, Bigi), secret: &Vec<u8>) {
+    pub fn get(conn: &DbConnection, public_key: &PublicKey,
+               data_group: &String, data_key: &String) -> Result<Option<Self>> {
+        Ok(block::table.filter(block::public_key.eq(public_key.to_hex()))
+                       .filter(block::data_group.eq(data_group))
+                       .filter(block::data_key.eq(data_key))
+                       .first(conn).optional()?.map(Self::with_data_block))
+    }
+
+    pub fn insert(conn: &DbConnection, public_key: &PublicKey, data_group: &String,
+                  data_key: &String, data_block: &String, data_encoding: DataEncoding,
+                  content_type: &Option<String>, data_version: &String, version_policy: VersionPolicy,
+                  signature: &SignatureHex, sig_scheme: SigScheme, secret: &Vec<u8>) -> Result<()> {
+        let (stored_block, data_bytes) = stored_data(data_block, data_encoding)?;
+        let raw_data = data_bytes.as_deref().unwrap_or(stored_block.as_bytes());
         diesel::insert_into(block::table).values((
-            block::public_key.eq(hex_from_point(public_key)),
+            block::public_key.eq(public_key.to_hex()),
             block::data_group.eq(data_group),
             block::data_key.eq(data_key),
-            block::data_block.eq(data_block),
+            block::data_block.eq(&stored_block),
             block::data_version.eq(data_version),
-            block::signature.eq(hex_from_bigi_pair(signature)),
+            block::signature.eq(signature.to_hex()),
             block::secret.eq(hex_from_bytes(secret)),
-        )).execute(conn).unwrap();
+            block::data_size.eq(raw_data.len() as i32),
+            block::data_hash.eq(hex_from_bytes(&hash_data_block(raw_data))),
+            block::sig_scheme.eq(sig_scheme.name()),
+            block::key_type.eq(public_key.key_type().name()),
+            block::data_encoding.eq(data_encoding.name()),
+            block::data_bytes.eq(&data_bytes),
+            block::content_type.eq(content_type),
+            block::version_policy.eq(version_policy.name()),
+        )).execute(conn).map_err(|err| match err {
+            // Somebody has inserted the same record in between
+            DatabaseError(DatabaseErrorKind::UniqueViolation, _) => Error::Conflict,
+            err => Error::from(err),
+        })?;
+        Ok(())
     }
 
-    pub fn delete(conn: &SqliteConnection, id: i32) {
-        diesel::delete(block::table.filter(block::id.eq(id))).execute(conn).unwrap();
+    pub fn delete(conn: &DbConnection, id: i32, old_secret: &String,
+                  delete_signature: &SignatureHex, delete_challenge: &str) -> Result<()> {
+        /* The record turns into a tombstone that keeps the last version, the secret
+           and the signature of the owner over it (or over the challenge),
+           so the deletion can be proven. It is done only if the secret has not been changed. */
+        let count = diesel::update(
+            block::table.filter(block::id.eq(id))
+                        .filter(block::secret.eq(old_secret))
+                        .filter(block::deleted.eq(false))
+        ).set((
+            block::data_block.eq(""),
+            block::data_bytes.eq(None::<Vec<u8>>),
+            block::deleted.eq(true),
+            block::delete_signature.eq(delete_signature.to_hex()),
+            block::delete_challenge.eq(delete_challenge),
+        )).execute(conn)?;
+        if count == 1 { Ok(()) } else { Err(Error::Conflict) }
     }
 
-    pub fn update(conn: &SqliteConnection, id: i32, data_block: &String,
-                  data_version: &String, signature: &(Bigi, Bigi), secret: &Vec<u8>) {
-        diesel::update(block::table.filter(block::id.eq(id))).set((
-            block::data_block.eq(data_block),
+    pub fn update(conn: &DbConnection, id: i32, old_secret: &String, data_block: &String,
+                  data_encoding: DataEncoding, content_type: &Option<String>, data_version: &String,
+                  version_policy: VersionPolicy, signature: &SignatureHex, sig_scheme: SigScheme,
+                  secret: &Vec<u8>) -> Result<()> {
+        /* Compare-and-swap on the secret, so the same secret cannot be used twice.
+           Updating a tombstone brings the record back. */
+        let (stored_block, data_bytes) = stored_data(data_block, data_encoding)?;
+        let raw_data = data_bytes.as_deref().unwrap_or(stored_block.as_bytes());
+        let count = diesel::update(
+            block::table.filter(block::id.eq(id)).filter(block::secret.eq(old_secret))
+        ).set((
+            block::data_block.eq(&stored_block),
             block::data_version.eq(data_version),
-            block::signature.eq(hex_from_bigi_pair(signature)),
+            block::signature.eq(signature.to_hex()),
             block::secret.eq(hex_from_bytes(secret)),
-        )).execute(conn).unwrap();
+            block::data_size.eq(raw_data.len() as i32),
+            block::data_hash.eq(hex_from_bytes(&hash_data_block(raw_data))),
+            block::deleted.eq(false),
+            block::delete_signature.eq(""),
+            block::delete_challenge.eq(""),
+            block::sig_scheme.eq(sig_scheme.name()),
+            block::data_encoding.eq(data_encoding.name()),
+            block::data_bytes.eq(&data_bytes),
+            block::content_type.eq(content_type),
+            block::previous_secret.eq(old_secret),
+            block::version_policy.eq(version_policy.name()),
+        )).execute(conn)?;
+        if count == 1 { Ok(()) } else { Err(Error::Conflict) }
+    }
+
+    fn paginate<'a, ST>(query: BoxedSelectStatement<'a, ST, block::table, DbBackend>,
+                        public_key: &PublicKey, data_group: &'a String,
+                        page: &'a Page) -> BoxedSelectStatement<'a, ST, block::table, DbBackend> {
+        /* Filters a group and takes one extra row to know if there is a next page */
+        let mut query = query.filter(block::public_key.eq(public_key.to_hex()))
+                             .filter(block::data_group.eq(data_group));
+        if !page.include_deleted {
+            query = query.filter(block::deleted.eq(false));
+        }
+        if let Some(prefix) = &page.prefix {
+            // Range instead of LIKE keeps the unique index in use and is case sensitive
+            query = query.filter(block::data_key.ge(prefix));
+            if let Some(upper_bound) = prefix_upper_bound(prefix) {
+                query = query.filter(block::data_key.lt(upper_bound));
+            }
+        }
+        if let Some(from) = &page.from {
+            query = query.filter(block::data_key.ge(from));
+        }
+        if let Some(to) = &page.to {
+            query = query.filter(block::data_key.lt(to));
+        }
+        query = match page.order {
+            Order::Key => query.order(block::data_key.asc()),
+            Order::Id => query.order(block::id.asc()),
+        };
+        if let Some(cursor) = &page.cursor {
+            query = match page.order {
+                Order::Key => query.filter(block::data_key.gt(cursor)),
+                Order::Id => query.filter(block::id.gt(page.cursor_id().unwrap_or(0))),
+            };
+        }
+        query.offset(page.offset).limit(page.limit + 1)
+    }
+
+    pub fn next_cursor<T, F>(records: &mut Vec<T>, page: &Page, key: F) -> Option<String>
+            where F: Fn(&T) -> (i32, &String) {
+        if records.len() as i64 > page.limit {
+            records.truncate(page.limit as usize);
+            records.last().map(|r| {
+                let (id, data_key) = key(r);
+                page.cursor_of(id, data_key)
+            })
+        } else {
+            None
+        }
     }
 }
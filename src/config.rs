@@ -0,0 +1,79 @@
+use bigi::Bigi;
+use bigi_ecc::Point;
+use rocket::Rocket;
+use rocket::config::{Config, ConfigError};
+use rocket::fairing::AdHoc;
+
+use crate::utils::try_hex_to_bigi;
+use crate::crypto::{generate_private_key, build_public_key};
+use crate::version::VersionPolicy;
+use crate::scheme::SigScheme;
+
+
+pub struct Settings {
+    pub version_policy: VersionPolicy,
+    pub log_private_key: Bigi,
+    pub log_public_key: Point,
+    pub require_nonce: bool,
+    pub secret_auth: bool,
+    pub min_sig_scheme: SigScheme,
+    pub reject_high_s: bool,
+}
+
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        /* Reads the extras of Rocket.toml (or ROCKET_* environment variables) */
+        let version_policy = match config.get_str("version_policy") {
+            Ok(value) => value.parse()?,
+            Err(ConfigError::Missing(_)) => VersionPolicy::None,
+            Err(err) => return Err(format!("version_policy: {}", err)),
+        };
+        let log_private_key = match config.get_str("log_private_key") {
+            Ok(value) => try_hex_to_bigi(value).map_err(|err| format!("log_private_key: {}", err))?,
+            Err(ConfigError::Missing(_)) => {
+                // Tree heads signed by a temporary key cannot be checked after a restart
+                eprintln!("Warning: log_private_key is not set, a temporary key is generated");
+                generate_private_key()
+            },
+            Err(err) => return Err(format!("log_private_key: {}", err)),
+        };
+        let log_public_key = build_public_key(&log_private_key);
+        let require_nonce = match config.get_bool("require_nonce") {
+            Ok(value) => value,
+            Err(ConfigError::Missing(_)) => false,
+            Err(err) => return Err(format!("require_nonce: {}", err)),
+        };
+        let secret_auth = match config.get_bool("secret_auth") {
+            Ok(value) => value,
+            Err(ConfigError::Missing(_)) => true,
+            Err(err) => return Err(format!("secret_auth: {}", err)),
+        };
+        let min_sig_scheme = match config.get_str("min_sig_scheme") {
+            Ok(value) => value.parse()?,
+            Err(ConfigError::Missing(_)) => SigScheme::V1,
+            Err(err) => return Err(format!("min_sig_scheme: {}", err)),
+        };
+        let reject_high_s = match config.get_bool("reject_high_s") {
+            Ok(value) => value,
+            Err(ConfigError::Missing(_)) => false,
+            Err(err) => return Err(format!("reject_high_s: {}", err)),
+        };
+        Ok(Settings {
+            version_policy, log_private_key, log_public_key, require_nonce, secret_auth, min_sig_scheme,
+            reject_high_s,
+        })
+    }
+
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_attach("Settings", |rocket: Rocket| {
+            match Settings::from_config(rocket.config()) {
+                Ok(settings) => Ok(rocket.manage(settings)),
+                Err(err) => {
+                    eprintln!("Invalid configuration: {}", err);
+                    Err(rocket)
+                }
+            }
+        })
+    }
+}
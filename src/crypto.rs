@@ -3,11 +3,26 @@ extern crate rand;
 use rand::Rng;
 use sha2::{Sha256, Digest};
 use bigi::Bigi;
-use bigi_ecc::schemas;
-use bigi_ecc::Point;
-use bigi_ecc::ecdsa::check_signature;
+use bigi_ecc::schemas::{self, Schema};
+use bigi_ecc::{point, Point};
+use bigi_ecc::base::CurveTrait;
+use bigi_ecc::ecdsa::build_signature;
 
 use crate::HASH_STORAGE_BITS;
+use crate::utils::*;
+use crate::keys::PublicKey;
+use crate::types::SignatureHex;
+
+// Field modulus of Secp256k1 in big-endian HEX
+const SECP256K1_P_HEX: &str = "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F";
+
+// Domain tag of the data signature v2, so the hash cannot collide with other signed values
+const DATA_SIGNATURE_V2_TAG: &[u8] = b"hash-storage-data-v2";
+
+lazy_static! {
+    // Loading the schema parses the curve parameters, so it is done once per process
+    pub static ref SECP256K1: Schema = schemas::load_secp256k1();
+}
 
 
 pub fn generate_secret() -> Vec<u8> {
@@ -17,17 +32,50 @@ pub fn generate_secret() -> Vec<u8> {
 }
 
 
-pub fn check_data_block_size(data_block: &String) -> bool {
+pub fn check_data_block_size(data_block: &[u8]) -> bool {
     data_block.len() < 16777216  // 2^24 bytes (or 16 MB)
 }
 
 
-pub fn check_data_signature(public_key: &Point,
+pub fn hash_data_block(data_block: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(data_block);
+    hasher.result().to_vec()
+}
+
+
+pub fn check_public_key(public_key: &Point) -> bool {
+    /* The point must be on the curve with coordinates reduced modulo p,
+       so the point at infinity and invalid-curve points are rejected */
+    let p = hex_to_bytes(SECP256K1_P_HEX);
+    bigi_to_be_bytes(&public_key.x) < p && bigi_to_be_bytes(&public_key.y) < p
+        && SECP256K1.curve.check(public_key)
+}
+
+
+pub fn decompress_public_key(is_odd: bool, x: &Bigi) -> Option<Point> {
+    /* Restores y from x choosing the root with the requested parity */
+    let (y1, y2) = SECP256K1.curve.find_y(x).ok()?;
+    let y = if (bigi_to_be_bytes(&y1)[HASH_STORAGE_BITS / 8 - 1] & 1 == 1) == is_odd {
+        y1
+    } else {
+        y2
+    };
+    let public_key = point!(*x, y);
+    if check_public_key(&public_key) {
+        Some(public_key)
+    } else {
+        None
+    }
+}
+
+
+pub fn check_data_signature(public_key: &PublicKey,
                             data_group: &String,
                             data_key: &String,
-                            data_block: &String,
+                            data_block: &[u8],
                             data_version: &String,
-                            signature: &(Bigi, Bigi)) -> bool {
+                            signature: &SignatureHex) -> bool {
     let hash = {
         let mut hasher = Sha256::new();
         hasher.input(data_group);
@@ -37,15 +85,96 @@ pub fn check_data_signature(public_key: &Point,
         hasher.result().to_vec()
     };
 
-    check_signature(&schemas::load_secp256k1(), public_key, &hash, signature)
+    public_key.verify(&hash, signature)
+}
+
+
+pub fn hash_data_v2(public_key: &PublicKey,
+                    data_group: &String,
+                    data_key: &String,
+                    data_block: &[u8],
+                    data_version: &String) -> Vec<u8> {
+    /* Fields are length-prefixed, so ("ab", "c") and ("a", "bc") differ,
+       and the public key (its type and canonical bytes) binds the signature to its owner */
+    let mut hasher = Sha256::new();
+    hasher.input(length_prefixed(&[
+        DATA_SIGNATURE_V2_TAG, public_key.key_type().name().as_bytes(), &public_key.to_signed_bytes(),
+        data_group.as_bytes(), data_key.as_bytes(), data_block, data_version.as_bytes(),
+    ]));
+    hasher.result().to_vec()
+}
+
+
+pub fn check_data_signature_v2(public_key: &PublicKey,
+                               data_group: &String,
+                               data_key: &String,
+                               data_block: &[u8],
+                               data_version: &String,
+                               signature: &SignatureHex) -> bool {
+    let hash = hash_data_v2(public_key, data_group, data_key, data_block, data_version);
+    public_key.verify(&hash, signature)
 }
 
 
-pub fn check_secret_signature(public_key: &Point,
+pub fn check_secret_signature(public_key: &PublicKey,
                               secret: &Vec<u8>,
-                              secret_signature: &(Bigi, Bigi)) -> bool {
-    check_signature(&schemas::load_secp256k1(), public_key,
-                    secret, secret_signature)
+                              secret_signature: &SignatureHex) -> bool {
+    public_key.verify(secret, secret_signature)
+}
+
+
+pub fn check_nonce_signature(public_key: &PublicKey,
+                             nonce: &Vec<u8>,
+                             data_block: &[u8],
+                             nonce_signature: &SignatureHex) -> bool {
+    /* The signature of the first insert is bound to the issued nonce */
+    let hash = {
+        let mut hasher = Sha256::new();
+        hasher.input(nonce);
+        hasher.input(hash_data_block(data_block));
+        hasher.result().to_vec()
+    };
+
+    public_key.verify(&hash, nonce_signature)
+}
+
+
+pub fn check_challenge_signature(public_key: &PublicKey,
+                                 challenge: &Vec<u8>,
+                                 operation: &str,
+                                 data_group: &String,
+                                 data_key: &String,
+                                 data_hash: &[u8],
+                                 challenge_signature: &SignatureHex) -> bool {
+    /* The challenge authorizes exactly one operation on one record,
+       data_hash is empty for deletes */
+    let hash = {
+        let mut hasher = Sha256::new();
+        hasher.input(length_prefixed(&[
+            challenge, operation.as_bytes(), data_group.as_bytes(), data_key.as_bytes(), data_hash,
+        ]));
+        hasher.result().to_vec()
+    };
+
+    public_key.verify(&hash, challenge_signature)
+}
+
+
+pub fn generate_private_key() -> Bigi {
+    let mut rng = rand::thread_rng();
+    SECP256K1.generate_pair(&mut rng).0
+}
+
+
+pub fn build_public_key(private_key: &Bigi) -> Point {
+    SECP256K1.curve.mul(&SECP256K1.generator, private_key)
+}
+
+
+pub fn sign_hash(private_key: &Bigi, hash: &[u8]) -> (Bigi, Bigi) {
+    /* Signs on behalf of the server (tree heads of the transparency log) */
+    let mut rng = rand::thread_rng();
+    build_signature(&mut rng, &SECP256K1, private_key, hash)
 }
 
 
@@ -53,7 +182,7 @@ pub fn check_secret_signature(public_key: &Point,
 mod tests {
     use super::*;
     use test::Bencher;
-    use bigi_ecc::ecdsa::build_signature;
+    use bigi_ecc::ecdsa::check_signature;
     // use crate::utils::*;
 
     #[test]
@@ -65,15 +194,87 @@ mod tests {
     #[test]
     fn test_check_data_block_size() {
         assert_eq!(
-            check_data_block_size(&String::from_utf8(vec![65; 100]).unwrap()),
+            check_data_block_size(&vec![65; 100]),
             true
         );
         assert_eq!(
-            check_data_block_size(&String::from_utf8(vec![65; 30000000]).unwrap()),
+            check_data_block_size(&vec![65; 30000000]),
             false
         );
     }
 
+    #[test]
+    fn test_check_public_key() {
+        let mut rng = rand::thread_rng();
+        let schema = schemas::load_secp256k1();
+        let (_, public_key) = schema.generate_pair(&mut rng);
+        assert_eq!(check_public_key(&public_key), true);
+
+        let mut wrong_key = public_key;
+        wrong_key.y = public_key.x;
+        assert_eq!(check_public_key(&wrong_key), false);
+
+        let zero = hex_to_point(&"0".repeat(128));
+        assert_eq!(check_public_key(&zero), false);
+    }
+
+    #[test]
+    fn test_decompress_public_key() {
+        let mut rng = rand::thread_rng();
+        let schema = schemas::load_secp256k1();
+        let (_, public_key) = schema.generate_pair(&mut rng);
+
+        let compressed = hex_from_point_compressed(&public_key);
+        let (is_odd, x) = try_hex_to_point_compressed(&compressed).unwrap();
+        let restored = decompress_public_key(is_odd, &x).unwrap();
+        assert_eq!(hex_from_point(&restored), hex_from_point(&public_key));
+    }
+
+    #[test]
+    fn test_check_nonce_signature() {
+        let private_key = generate_private_key();
+        let public_key = PublicKey::Secp256k1(build_public_key(&private_key));
+        let nonce = generate_secret();
+        let data_block = "My shared data block".to_string();
+        let hash = {
+            let mut hasher = Sha256::new();
+            hasher.input(&nonce);
+            hasher.input(hash_data_block(data_block.as_bytes()));
+            hasher.result().to_vec()
+        };
+        let nonce_signature = SignatureHex::from_pair(sign_hash(&private_key, &hash));
+        assert_eq!(check_nonce_signature(&public_key, &nonce, data_block.as_bytes(), &nonce_signature), true);
+        assert_eq!(check_nonce_signature(&public_key, &generate_secret(), data_block.as_bytes(), &nonce_signature), false);
+    }
+
+    #[test]
+    fn test_check_challenge_signature() {
+        let private_key = generate_private_key();
+        let public_key = PublicKey::Secp256k1(build_public_key(&private_key));
+        let challenge = generate_secret();
+        let data_group = "My group".to_string();
+        let data_key = "My data key".to_string();
+        let hash = {
+            let mut hasher = Sha256::new();
+            hasher.input(length_prefixed(&[&challenge, b"delete", data_group.as_bytes(), data_key.as_bytes(), b""]));
+            hasher.result().to_vec()
+        };
+        let challenge_signature = SignatureHex::from_pair(sign_hash(&private_key, &hash));
+        assert_eq!(check_challenge_signature(&public_key, &challenge, "delete", &data_group, &data_key, b"", &challenge_signature), true);
+        assert_eq!(check_challenge_signature(&public_key, &challenge, "save", &data_group, &data_key, b"", &challenge_signature), false);
+        assert_eq!(check_challenge_signature(&public_key, &challenge, "delete", &data_key, &data_group, b"", &challenge_signature), false);
+    }
+
+    #[test]
+    fn test_sign_hash() {
+        let private_key = generate_private_key();
+        let public_key = build_public_key(&private_key);
+        let hash = generate_secret();
+        let signature = sign_hash(&private_key, &hash);
+        assert_eq!(check_signature(&schemas::load_secp256k1(), &public_key, &hash, &signature), true);
+        assert_eq!(check_signature(&schemas::load_secp256k1(), &public_key, &generate_secret(), &signature), false);
+    }
+
     #[test]
     fn test_check_data_signature() {
         // Initialization
@@ -82,6 +283,7 @@ mod tests {
 
         // Generating keys
         let (private_key, public_key) = schema.generate_pair(&mut rng);
+        let public_key = PublicKey::Secp256k1(public_key);
 
         // Initial data
         let data_group: String = "My group".to_string();
@@ -98,7 +300,7 @@ mod tests {
             hasher.input(&data_version);
             let hash = hasher.result().to_vec();
 
-            build_signature(&mut rng, &schema, &private_key, &hash)
+            SignatureHex::from_pair(build_signature(&mut rng, &schema, &private_key, &hash))
         };
 
         // let secret = generate_secret();
@@ -115,9 +317,59 @@ mod tests {
         // Checking
         assert_eq!(
             check_data_signature(&public_key, &data_group, &data_key,
-                                 &data_block, &data_version, &signature),
+                                 data_block.as_bytes(), &data_version, &signature),
+            true
+        );
+    }
+
+    #[test]
+    fn test_check_data_signature_v2() {
+        let private_key = generate_private_key();
+        let public_key = PublicKey::Secp256k1(build_public_key(&private_key));
+        let data_group: String = "ab".to_string();
+        let data_key: String = "c".to_string();
+        let data_block: String = "My shared data block".to_string();
+        let data_version: String = "1".to_string();
+
+        let hash = hash_data_v2(&public_key, &data_group, &data_key, data_block.as_bytes(), &data_version);
+        assert_ne!(hash, hash_data_v2(&public_key, &"a".to_string(), &"bc".to_string(), data_block.as_bytes(), &data_version));
+
+        let signature = SignatureHex::from_pair(sign_hash(&private_key, &hash));
+        assert_eq!(
+            check_data_signature_v2(&public_key, &data_group, &data_key,
+                                    data_block.as_bytes(), &data_version, &signature),
             true
         );
+        assert_eq!(
+            check_data_signature(&public_key, &data_group, &data_key,
+                                 data_block.as_bytes(), &data_version, &signature),
+            false
+        );
+    }
+
+    #[test]
+    fn test_hash_data_v2_public_key() {
+        // The same P-256 key in both forms, the hash covers its type and the SEC1 compressed point
+        let compressed = PublicKey::from_hex(
+            "p256:036B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C296"
+        ).unwrap();
+        let uncompressed = PublicKey::from_hex(&compressed.to_hex()).unwrap();
+        let (data_group, data_key, data_version) = ("group".to_string(), "key".to_string(), "1".to_string());
+        let hash = hash_data_v2(&compressed, &data_group, &data_key, b"block", &data_version);
+        assert_eq!(hash, hash_data_v2(&uncompressed, &data_group, &data_key, b"block", &data_version));
+        let expected = Sha256::digest(&length_prefixed(&[
+            b"hash-storage-data-v2", b"p256",
+            &hex_to_bytes("036B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C296"),
+            b"group", b"key", b"block", b"1",
+        ])).to_vec();
+        assert_eq!(hash, expected);
+
+        let ed25519 = PublicKey::from_hex(
+            "ed25519:D75A980182B10AB7D54BFED3C964073A0EE172F3DAA62325AF021A68F707511A"
+        ).unwrap();
+        assert_eq!(ed25519.to_signed_bytes(), hex_to_bytes(
+            "D75A980182B10AB7D54BFED3C964073A0EE172F3DAA62325AF021A68F707511A"
+        ));
     }
 
     #[test]
@@ -128,12 +380,13 @@ mod tests {
 
         // Generating keys
         let (private_key, public_key) = schema.generate_pair(&mut rng);
+        let public_key = PublicKey::Secp256k1(public_key);
 
         // Initial data
         let secret = generate_secret();
 
         // Building secret_signature
-        let secret_signature = build_signature(&mut rng, &schema, &private_key, &secret);
+        let secret_signature = SignatureHex::from_pair(build_signature(&mut rng, &schema, &private_key, &secret));
 
         // Checking
         assert_eq!(
@@ -150,7 +403,7 @@ mod tests {
     #[bench]
     fn bench_check_data_block_size(b: &mut Bencher) {
         let data_block = &String::from_utf8(vec![65; 1000000]).unwrap();
-        b.iter(|| check_data_block_size(&data_block));
+        b.iter(|| check_data_block_size(data_block.as_bytes()));
     }
 
     #[bench]
@@ -161,6 +414,7 @@ mod tests {
 
         // Generating keys
         let (private_key, public_key) = schema.generate_pair(&mut rng);
+        let public_key = PublicKey::Secp256k1(public_key);
 
         // Initial data
         let data_group: String = "My group".to_string();
@@ -177,13 +431,13 @@ mod tests {
             hasher.input(&data_version);
             let hash = hasher.result().to_vec();
 
-            build_signature(&mut rng, &schema, &private_key, &hash)
+            SignatureHex::from_pair(build_signature(&mut rng, &schema, &private_key, &hash))
         };
 
         // Benchmark
         b.iter(||
             check_data_signature(&public_key, &data_group, &data_key,
-                                 &data_block, &data_version, &signature)
+                                 data_block.as_bytes(), &data_version, &signature)
         );
     }
 
@@ -195,14 +449,40 @@ mod tests {
 
         // Generating keys
         let (private_key, public_key) = schema.generate_pair(&mut rng);
+        let public_key = PublicKey::Secp256k1(public_key);
 
         // Initial data
         let secret = generate_secret();
 
         // Building secret_signature
-        let secret_signature = build_signature(&mut rng, &schema, &private_key, &secret);
+        let secret_signature = SignatureHex::from_pair(build_signature(&mut rng, &schema, &private_key, &secret));
 
         // Benchmark
         b.iter(|| check_secret_signature(&public_key, &secret, &secret_signature));
     }
+
+    #[bench]
+    fn bench_load_secp256k1(b: &mut Bencher) {
+        // The cost that each verification paid before the schema was cached
+        b.iter(|| schemas::load_secp256k1());
+    }
+
+    #[bench]
+    fn bench_check_secret_signature_batch(b: &mut Bencher) {
+        // Initialization
+        let mut rng = rand::thread_rng();
+
+        // A batch of the maximum size signed by different keys
+        let batch: Vec<(PublicKey, Vec<u8>, SignatureHex)> = (0..crate::MAX_BATCH_SIZE).map(|_| {
+            let (private_key, public_key) = SECP256K1.generate_pair(&mut rng);
+            let secret = generate_secret();
+            let secret_signature = SignatureHex::from_pair(build_signature(&mut rng, &SECP256K1, &private_key, &secret));
+            (PublicKey::Secp256k1(public_key), secret, secret_signature)
+        }).collect();
+
+        // Benchmark, compare with bench_check_secret_signature multiplied by the batch size
+        b.iter(|| par_map(&batch, |(public_key, secret, secret_signature)| {
+            check_secret_signature(public_key, secret, secret_signature)
+        }));
+    }
 }
@@ -1,32 +1,218 @@
+use std::env;
 use std::ops::Deref;
+use std::time::Duration;
 use rocket::http::Status;
 use rocket::request::{self, FromRequest};
 use rocket::{Request, State, Outcome};
+use rocket::config::{Config, ConfigError};
 
 use r2d2;
 use r2d2_diesel::ConnectionManager;
 
-use diesel::sqlite::SqliteConnection;
+#[cfg(feature = "mysql")]
+use diesel::Connection as _;
+#[cfg(any(all(feature = "sqlite", not(feature = "postgres")), feature = "mysql"))]
+use diesel::connection::SimpleConnection;
 
+use diesel::prelude::*;
 
-pub type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
-static DATABASE_URL: &'static str = env!("DATABASE_URL");
+use crate::block::Block;
+use crate::transparency::LogLeaf;
+use crate::schema::backfill;
 
+#[cfg(not(any(feature = "sqlite", feature = "postgres", feature = "mysql")))]
+compile_error!("one of the features sqlite, postgres or mysql must be enabled");
 
-pub fn connect() -> Pool {
-    let manager = ConnectionManager::<SqliteConnection>::new(DATABASE_URL);
-    r2d2::Pool::builder().build(manager).expect("Failed to create pool")
+#[cfg(all(feature = "postgres", feature = "mysql"))]
+compile_error!("only one of the features postgres or mysql can be enabled");
+
+// The backend is chosen at build time, the default one is SQLite.
+// PostgreSQL and MySQL take over the default one, so --no-default-features is not required
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+pub use diesel::sqlite::{Sqlite as DbBackend, SqliteConnection as DbConnection};
+#[cfg(feature = "postgres")]
+pub use diesel::pg::{Pg as DbBackend, PgConnection as DbConnection};
+#[cfg(feature = "mysql")]
+pub use diesel::mysql::{Mysql as DbBackend, MysqlConnection as DbConnection};
+
+pub type Pool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+const JOURNAL_MODES: &[&str] = &["delete", "truncate", "persist", "memory", "wal", "off"];
+const SYNCHRONOUS_LEVELS: &[&str] = &["off", "normal", "full", "extra"];
+
+
+#[derive(Debug)]
+pub struct DbConfig {
+    pub database_url: String,
+    pub pool_size: u32,
+    pub connection_timeout: u64,
+    pub journal_mode: String,
+    pub busy_timeout: u32,
+    pub synchronous: String,
+}
+
+
+impl DbConfig {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        /* Reads the extras of Rocket.toml (or ROCKET_* environment variables),
+           DATABASE_URL of the environment is used if database_url is not set */
+        let database_url = match config.get_str("database_url") {
+            Ok(value) => value.to_string(),
+            Err(ConfigError::Missing(_)) => env::var("DATABASE_URL").map_err(
+                |_| "database_url is not set and DATABASE_URL is missing".to_string()
+            )?,
+            Err(err) => return Err(format!("database_url: {}", err)),
+        };
+        let pool_size = match config.get_int("pool_size") {
+            Ok(value) if value > 0 && value <= u32::max_value() as i64 => value as u32,
+            Ok(_) => return Err("pool_size: must be positive".to_string()),
+            Err(ConfigError::Missing(_)) => 10,
+            Err(err) => return Err(format!("pool_size: {}", err)),
+        };
+        let connection_timeout = match config.get_int("connection_timeout") {
+            Ok(value) if value > 0 => value as u64,
+            Ok(_) => return Err("connection_timeout: must be positive".to_string()),
+            Err(ConfigError::Missing(_)) => 30,
+            Err(err) => return Err(format!("connection_timeout: {}", err)),
+        };
+        let journal_mode = match config.get_str("journal_mode") {
+            Ok(value) => check_choice(value, JOURNAL_MODES).map_err(|err| format!("journal_mode: {}", err))?,
+            Err(ConfigError::Missing(_)) => "wal".to_string(),
+            Err(err) => return Err(format!("journal_mode: {}", err)),
+        };
+        let busy_timeout = match config.get_int("busy_timeout") {
+            Ok(value) if value >= 0 && value <= u32::max_value() as i64 => value as u32,
+            Ok(_) => return Err("busy_timeout: must not be negative".to_string()),
+            Err(ConfigError::Missing(_)) => 5000,
+            Err(err) => return Err(format!("busy_timeout: {}", err)),
+        };
+        let synchronous = match config.get_str("synchronous") {
+            Ok(value) => check_choice(value, SYNCHRONOUS_LEVELS).map_err(|err| format!("synchronous: {}", err))?,
+            Err(ConfigError::Missing(_)) => "normal".to_string(),
+            Err(err) => return Err(format!("synchronous: {}", err)),
+        };
+        Ok(DbConfig { database_url, pool_size, connection_timeout, journal_mode, busy_timeout, synchronous })
+    }
+
+    #[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+    pub fn pragmas(&self) -> String {
+        format!(
+            "PRAGMA journal_mode = {}; PRAGMA busy_timeout = {}; PRAGMA synchronous = {};",
+            self.journal_mode, self.busy_timeout, self.synchronous
+        )
+    }
+
+    pub fn connect(&self) -> Result<Pool, String> {
+        /* Fails if the first connection cannot be established, so a wrong URL is reported on start */
+        let manager = ConnectionManager::<DbConnection>::new(self.database_url.as_str());
+        let builder = r2d2::Pool::builder()
+            .max_size(self.pool_size)
+            .connection_timeout(Duration::from_secs(self.connection_timeout));
+        // The pragmas are specific to SQLite, server databases are configured on their side
+        #[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+        let builder = builder.connection_customizer(Box::new(Pragmas(self.pragmas())));
+        builder.build(manager)
+            .map_err(|err| format!("{}: {}", self.database_url, err))
+    }
+
+    pub fn pool(config: &Config) -> Result<Pool, String> {
+        /* The pool of the configured database with the pending backfills done */
+        let pool = DbConfig::from_config(config)?.connect()?;
+        {
+            let conn = pool.get().map_err(|err| err.to_string())?;
+            run_backfills(&conn)?;
+        }
+        Ok(pool)
+    }
+}
+
+
+fn run_backfills(conn: &DbConnection) -> Result<(), String> {
+    /* The metadata of old blocks and the nodes of old log leaves are filled once,
+       the migrations list them in the backfill table and they are removed when done,
+       so the usual start reads an empty table instead of scanning the data */
+    let pending: Vec<String> = backfill::table.select(backfill::name).load(conn)
+        .map_err(|err| format!("failed to read backfills: {}", err))?;
+    for name in pending.iter() {
+        let result = match name.as_str() {
+            "block_meta" => Block::fill_meta(conn).map(|_| ()),
+            "log_node" => write_transaction(conn, || LogLeaf::fill_nodes(conn)).map(|_| ()),
+            // Left for the version of the server that knows it
+            _ => continue,
+        };
+        result.map_err(|err| format!("failed to run backfill {}: {}", name, err))?;
+        diesel::delete(backfill::table.filter(backfill::name.eq(name))).execute(conn)
+            .map_err(|err| format!("failed to run backfill {}: {}", name, err))?;
+    }
+    Ok(())
+}
+
+
+fn check_choice(value: &str, choices: &[&str]) -> Result<String, String> {
+    /* The value goes into a PRAGMA statement, so only the known words are allowed */
+    let value = value.to_lowercase();
+    if choices.contains(&value.as_str()) {
+        Ok(value)
+    } else {
+        Err(format!("must be one of {}", choices.join(", ")))
+    }
+}
+
+
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+#[derive(Debug)]
+struct Pragmas(String);
+
+
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+impl r2d2::CustomizeConnection<DbConnection, r2d2_diesel::Error> for Pragmas {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), r2d2_diesel::Error> {
+        conn.batch_execute(&self.0).map_err(r2d2_diesel::Error::QueryError)
+    }
+}
+
+
+/* Transactions that are not interleaved with other writes, so the checks made inside
+   them (secrets, versions, the size of the log) stay true until they commit */
+
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+pub fn write_transaction<T, E, F>(conn: &DbConnection, f: F) -> Result<T, E>
+    where F: FnOnce() -> Result<T, E>, E: From<diesel::result::Error> {
+    conn.immediate_transaction(f)
 }
 
 
-pub struct Connection(pub r2d2::PooledConnection<ConnectionManager<SqliteConnection>>);
+#[cfg(feature = "postgres")]
+pub fn write_transaction<T, E, F>(conn: &DbConnection, f: F) -> Result<T, E>
+    where F: FnOnce() -> Result<T, E>, E: From<diesel::result::Error> {
+    // A transaction that loses to a concurrent one fails with SerializationFailure,
+    // it is reported as conflict (see From<diesel::result::Error> for Error)
+    conn.build_transaction().serializable().run(f)
+}
+
+
+#[cfg(feature = "mysql")]
+pub fn write_transaction<T, E, F>(conn: &DbConnection, f: F) -> Result<T, E>
+    where F: FnOnce() -> Result<T, E>, E: From<diesel::result::Error> {
+    // SET TRANSACTION applies to the next transaction of the session only,
+    // a deadlock between concurrent ones is reported as conflict
+    conn.batch_execute("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")?;
+    conn.transaction(f)
+}
+
+
+pub struct Connection(pub r2d2::PooledConnection<ConnectionManager<DbConnection>>);
 
 
 impl<'a, 'r> FromRequest<'a, 'r> for Connection {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Connection, ()> {
-        let pool = request.guard::<State<Pool>>()?;
+        // The pool is missing if the instance keeps the records in memory
+        let pool = match request.guard::<State<Pool>>() {
+            Outcome::Success(pool) => pool,
+            _ => return Outcome::Failure((Status::ServiceUnavailable, ())),
+        };
         match pool.get() {
             Ok(conn) => Outcome::Success(Connection(conn)),
             Err(_) => Outcome::Failure((Status::ServiceUnavailable, ()))
@@ -36,9 +222,144 @@ impl<'a, 'r> FromRequest<'a, 'r> for Connection {
 
 
 impl Deref for Connection {
-    type Target = SqliteConnection;
+    type Target = DbConnection;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
+
+
+/* A database with the migrations applied for the tests of the Storage implementation:
+   an in-memory one of SQLite, or a new schema in DATABASE_URL for PostgreSQL and MySQL */
+
+#[cfg(test)]
+pub mod testing {
+    #[cfg(not(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql")))))]
+    use std::env;
+    use std::fs;
+    use std::ops::Deref;
+    use diesel::Connection as _;
+    use diesel::connection::SimpleConnection as _;
+
+    use super::DbConnection;
+    #[cfg(not(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql")))))]
+    use crate::{utils::hex_from_bytes, crypto::generate_secret};
+
+    #[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+    const MIGRATIONS: &str = "sqlite";
+    #[cfg(feature = "postgres")]
+    const MIGRATIONS: &str = "postgres";
+    #[cfg(feature = "mysql")]
+    const MIGRATIONS: &str = "mysql";
+
+    pub struct TestConnection {
+        conn: DbConnection,
+        // The schema (the database of MySQL) of the test, it is dropped at the end
+        #[cfg_attr(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))), allow(dead_code))]
+        schema: Option<String>,
+    }
+
+    impl TestConnection {
+        pub fn new() -> Option<Self> {
+            /* None if the server database is not given, so its tests are skipped */
+            #[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+            let test = TestConnection { conn: DbConnection::establish(":memory:").unwrap(), schema: None };
+            #[cfg(not(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql")))))]
+            let test = {
+                let database_url = match env::var("DATABASE_URL") {
+                    Ok(database_url) => database_url,
+                    Err(_) => {
+                        eprintln!("DATABASE_URL is not set, the test is skipped");
+                        return None;
+                    }
+                };
+                let conn = DbConnection::establish(&database_url).unwrap();
+                let schema = format!("test_{}", hex_from_bytes(&generate_secret()[..8]).to_lowercase());
+                #[cfg(feature = "postgres")]
+                conn.batch_execute(&format!("CREATE SCHEMA {0}; SET search_path TO {0};", schema)).unwrap();
+                #[cfg(feature = "mysql")]
+                conn.batch_execute(&format!("CREATE DATABASE {0}; USE {0};", schema)).unwrap();
+                TestConnection { conn, schema: Some(schema) }
+            };
+            test.migrate();
+            Some(test)
+        }
+
+        fn migrate(&self) {
+            /* up.sql of every migration in the order of their names */
+            let dir = format!("{}/migrations/{}", env!("CARGO_MANIFEST_DIR"), MIGRATIONS);
+            let mut migrations: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+            migrations.sort();
+            for migration in migrations {
+                let sql = fs::read_to_string(migration.join("up.sql")).unwrap();
+                self.conn.batch_execute(&sql).unwrap();
+            }
+        }
+    }
+
+    impl Deref for TestConnection {
+        type Target = DbConnection;
+
+        fn deref(&self) -> &Self::Target {
+            &self.conn
+        }
+    }
+
+    #[cfg(not(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql")))))]
+    impl Drop for TestConnection {
+        fn drop(&mut self) {
+            if let Some(schema) = &self.schema {
+                #[cfg(feature = "postgres")]
+                let _ = self.conn.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema));
+                #[cfg(feature = "mysql")]
+                let _ = self.conn.batch_execute(&format!("DROP DATABASE {}", schema));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use crate::schema::block;
+    use crate::storage::Storage;
+    use crate::keys::PublicKey;
+    use crate::types::SignatureHex;
+    use crate::scheme::SigScheme;
+    use crate::encoding::DataEncoding;
+    use crate::version::VersionPolicy;
+    use crate::crypto::generate_secret;
+    use super::testing::TestConnection;
+
+    #[test]
+    fn test_run_backfills() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let pending = || backfill::table.select(backfill::name).order(backfill::name.asc())
+                                        .load::<String>(&*conn).unwrap();
+        let data_hash = || block::table.select(block::data_hash).first::<String>(&*conn).unwrap();
+        assert_eq!(pending(), vec!["block_meta", "log_node"]);
+
+        let public_key = PublicKey::from_hex(
+            "ed25519:D75A980182B10AB7D54BFED3C964073A0EE172F3DAA62325AF021A68F707511A"
+        ).unwrap();
+        let record = (&*conn as &dyn Storage).insert(
+            &public_key, &"group".to_string(), &"key".to_string(), &"block".to_string(), DataEncoding::Utf8,
+            &None, &"1".to_string(), VersionPolicy::None, &SignatureHex::try_from("0".repeat(128)).unwrap(),
+            SigScheme::V1, &generate_secret()
+        ).unwrap();
+        // The record as an older version has written it
+        diesel::update(block::table).set(block::data_hash.eq("")).execute(&*conn).unwrap();
+
+        run_backfills(&conn).unwrap();
+        assert!(pending().is_empty());
+        assert_eq!(data_hash(), record.data_hash);
+
+        // Once done, the backfills are not run again
+        diesel::update(block::table).set(block::data_hash.eq("")).execute(&*conn).unwrap();
+        run_backfills(&conn).unwrap();
+        assert_eq!(data_hash(), "");
+    }
+}
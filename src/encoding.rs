@@ -0,0 +1,81 @@
+use std::str::FromStr;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::error::{Error, Result};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataEncoding {
+    Utf8,
+    Base64,
+}
+
+
+impl Default for DataEncoding {
+    fn default() -> Self {
+        // Clients that do not send data_encoding store text
+        DataEncoding::Utf8
+    }
+}
+
+
+impl FromStr for DataEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(DataEncoding::Utf8),
+            "base64" => Ok(DataEncoding::Base64),
+            _ => Err(format!("unknown data encoding {:?}", s)),
+        }
+    }
+}
+
+
+impl DataEncoding {
+    pub fn decode(&self, data_block: &str) -> Result<Vec<u8>> {
+        /* The bytes the signature and data_hash are built from */
+        match self {
+            DataEncoding::Utf8 => Ok(data_block.as_bytes().to_vec()),
+            DataEncoding::Base64 => base64::decode(data_block).map_err(
+                |err| Error::BadEncoding(format!("data_block is not valid base64: {}", err))
+            ),
+        }
+    }
+
+    pub fn encode(&self, data_bytes: &[u8]) -> String {
+        match self {
+            DataEncoding::Utf8 => String::from_utf8_lossy(data_bytes).into_owned(),
+            DataEncoding::Base64 => base64::encode(data_bytes),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataEncoding::Utf8 => "utf8",
+            DataEncoding::Base64 => "base64",
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(DataEncoding::Utf8.decode("Hi").unwrap(), b"Hi".to_vec());
+        assert_eq!(DataEncoding::Base64.decode("AP8=").unwrap(), vec![0, 255]);
+        assert_eq!(DataEncoding::Base64.encode(&[0, 255]), "AP8=");
+        assert!(DataEncoding::Base64.decode("A").is_err());
+        assert!(DataEncoding::Base64.decode("Hi there").is_err());
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("base64".parse::<DataEncoding>().unwrap(), DataEncoding::Base64);
+        assert!("hex".parse::<DataEncoding>().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::Json;
+use serde_json::{json, Value};
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+
+
+#[derive(Debug)]
+pub enum Error {
+    BadInput,
+    BadQuery(String),
+    BadHex(String),
+    BadPublicKey,
+    BadSignature,
+    BadSecretSignature,
+    NotFound,
+    Conflict,
+    BadVersion(String),
+    StaleVersion,
+    BlockTooLarge,
+    BadNonce(String),
+    BadChallenge(String),
+    BadSigScheme(String),
+    HighS,
+    BadEncoding(String),
+    BadContentType(String),
+    NotSupported(String),
+    Database(diesel::result::Error),
+    Batch(Vec<(usize, Error)>),
+}
+
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BadInput => "bad_input",
+            Error::BadQuery(_) => "bad_query",
+            Error::BadHex(_) => "bad_hex",
+            Error::BadPublicKey => "bad_public_key",
+            Error::BadSignature => "bad_signature",
+            Error::BadSecretSignature => "bad_secret_signature",
+            Error::NotFound => "not_found",
+            Error::Conflict => "conflict",
+            Error::BadVersion(_) => "bad_version",
+            Error::StaleVersion => "stale_version",
+            Error::BlockTooLarge => "block_too_large",
+            Error::BadNonce(_) => "bad_nonce",
+            Error::BadChallenge(_) => "bad_challenge",
+            Error::BadSigScheme(_) => "bad_sig_scheme",
+            Error::HighS => "high_s_signature",
+            Error::BadEncoding(_) => "bad_encoding",
+            Error::BadContentType(_) => "bad_content_type",
+            Error::NotSupported(_) => "not_supported",
+            Error::Database(_) => "database_error",
+            Error::Batch(_) => "batch_failed",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            Error::BadInput => Status::BadRequest,
+            Error::BadQuery(_) => Status::BadRequest,
+            Error::BadHex(_) => Status::BadRequest,
+            Error::BadPublicKey => Status::BadRequest,
+            Error::BadSignature => Status::Forbidden,
+            Error::BadSecretSignature => Status::Forbidden,
+            Error::NotFound => Status::NotFound,
+            Error::Conflict => Status::Conflict,
+            Error::BadVersion(_) => Status::BadRequest,
+            Error::StaleVersion => Status::Conflict,
+            Error::BlockTooLarge => Status::PayloadTooLarge,
+            Error::BadNonce(_) => Status::Forbidden,
+            Error::BadChallenge(_) => Status::Forbidden,
+            Error::BadSigScheme(_) => Status::BadRequest,
+            Error::HighS => Status::BadRequest,
+            Error::BadEncoding(_) => Status::BadRequest,
+            Error::BadContentType(_) => Status::BadRequest,
+            Error::NotSupported(_) => Status::NotImplemented,
+            Error::Database(_) => Status::InternalServerError,
+            // The most severe status of the items, so a failure of the server is not reported as 400
+            Error::Batch(errors) => errors.iter().map(|(_, err)| err.status())
+                                          .max_by_key(|status| status.code)
+                                          .unwrap_or(Status::BadRequest),
+        }
+    }
+
+    fn log(&self) {
+        // Driver details are hidden from the clients, so they go to the server log
+        match self {
+            Error::Database(err) => error_!("Database error: {}", err),
+            Error::Batch(errors) => errors.iter().for_each(|(_, err)| err.log()),
+            _ => {},
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut body = json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+        if let Error::Batch(errors) = self {
+            body["items"] = json!(errors.iter().map(|(index, err)| {
+                let mut item = err.to_json();
+                item["index"] = json!(index);
+                item
+            }).collect::<Vec<Value>>());
+        }
+        body
+    }
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadInput => write!(f, "request body is malformed"),
+            Error::BadQuery(message) => write!(f, "invalid query parameter: {}", message),
+            Error::BadHex(message) => write!(f, "invalid hex value: {}", message),
+            Error::BadPublicKey => write!(f, "public key is not a valid key of its type"),
+            Error::BadSignature => write!(f, "data signature is invalid"),
+            Error::BadSecretSignature => write!(f, "secret signature is invalid"),
+            Error::NotFound => write!(f, "record not found"),
+            Error::Conflict => write!(f, "record has been changed concurrently, the secret is already used"),
+            Error::BadVersion(message) => write!(f, "invalid data_version: {}", message),
+            Error::StaleVersion => write!(f, "data_version must be greater than the stored one"),
+            Error::BlockTooLarge => write!(f, "data block exceeds 16 MB"),
+            Error::BadNonce(message) => write!(f, "{}", message),
+            Error::BadChallenge(message) => write!(f, "{}", message),
+            Error::BadSigScheme(message) => write!(f, "invalid sig_scheme: {}", message),
+            Error::HighS => write!(f, "signature is not canonical, s must be in the lower half of the curve order"),
+            Error::BadEncoding(message) => write!(f, "{}", message),
+            Error::BadContentType(message) => write!(f, "invalid content_type: {}", message),
+            Error::NotSupported(message) => write!(f, "{}", message),
+            // Driver details are not exposed to the clients
+            Error::Database(_) => write!(f, "database failure"),
+            Error::Batch(errors) => write!(f, "{} records failed, nothing is saved", errors.len()),
+        }
+    }
+}
+
+
+impl From<diesel::result::Error> for Error {
+    fn from(err: diesel::result::Error) -> Self {
+        /* A serializable transaction of PostgreSQL or MySQL that has lost to a concurrent one
+           is a conflict, the same as a consumed secret, so the client reloads the record */
+        match &err {
+            DatabaseError(DatabaseErrorKind::SerializationFailure, _) => Error::Conflict,
+            // Diesel has no kind for deadlocks, MySQL reports them this way under SERIALIZABLE
+            DatabaseError(_, info) if info.message().starts_with("Deadlock found")
+                || info.message().starts_with("deadlock detected") => Error::Conflict,
+            _ => Error::Database(err),
+        }
+    }
+}
+
+
+impl<'r> Responder<'r> for Error {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        self.log();
+        let body = Json(self.to_json());
+        Response::build_from(body.respond_to(request)?)
+            .status(self.status())
+            .ok()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_status() {
+        assert_eq!(Error::Batch(vec![]).status(), Status::BadRequest);
+        assert_eq!(Error::Batch(vec![(0, Error::BadSignature)]).status(), Status::Forbidden);
+        let errors = vec![(0, Error::BadInput), (1, Error::Conflict), (2, Error::BadSignature)];
+        assert_eq!(Error::Batch(errors).status(), Status::Conflict);
+        let errors = vec![(0, Error::Conflict), (1, Error::Database(diesel::result::Error::NotFound))];
+        assert_eq!(Error::Batch(errors).status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn test_from_database_error() {
+        let error = |kind, message: &str| DatabaseError(kind, Box::new(message.to_string()));
+        let err = Error::from(error(DatabaseErrorKind::SerializationFailure, "could not serialize access"));
+        assert!(matches!(err, Error::Conflict));
+        let err = Error::from(error(
+            DatabaseErrorKind::__Unknown, "Deadlock found when trying to get lock; try restarting transaction"
+        ));
+        assert!(matches!(err, Error::Conflict));
+        let err = Error::from(error(DatabaseErrorKind::UniqueViolation, "UNIQUE constraint failed"));
+        assert!(matches!(err, Error::Database(_)));
+        assert!(matches!(Error::from(diesel::result::Error::NotFound), Error::Database(_)));
+    }
+}
@@ -0,0 +1,205 @@
+use serde_derive::{Serialize, Deserialize};
+use diesel;
+use diesel::prelude::*;
+
+use crate::utils::*;
+use crate::db::DbConnection;
+use crate::keys::PublicKey;
+use crate::error::Result;
+use crate::block::Block;
+use crate::encoding::DataEncoding;
+use crate::schema::block_history;
+
+pub const MAX_HISTORY_PAGE_SIZE: i64 = 100;
+
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct History {
+    pub id: i32,
+    pub block_id: i32,
+    pub public_key: String,
+    pub data_group: String,
+    pub data_key: String,
+    pub data_block: String,
+    pub data_version: String,
+    pub signature: String,
+    pub data_size: i32,
+    pub data_hash: String,
+    pub action: String,
+    pub archived_at: i64,
+    pub sig_scheme: String,
+    pub data_encoding: String,
+    #[serde(skip)]
+    pub data_bytes: Option<Vec<u8>>,
+    // Set for an archived tombstone only
+    pub delete_signature: String,
+    pub delete_challenge: String,
+}
+
+
+impl History {
+    pub fn compress_public_key(&mut self) {
+        self.public_key = PublicKey::compress_stored_hex(&self.public_key);
+    }
+
+    pub fn archive(conn: &DbConnection, record: &Block, action: &str) -> Result<()> {
+        /* Saves the signed state of the record before it is updated or deleted,
+           a tombstone is archived with the signature of its deletion before it is revived */
+        diesel::insert_into(block_history::table).values((
+            block_history::block_id.eq(record.id),
+            block_history::public_key.eq(&record.public_key),
+            block_history::data_group.eq(&record.data_group),
+            block_history::data_key.eq(&record.data_key),
+            // Base64 blocks are archived as their raw bytes, the same way they are stored
+            block_history::data_block.eq(if record.data_bytes.is_some() { "" } else { &record.data_block }),
+            block_history::data_version.eq(&record.data_version),
+            block_history::signature.eq(&record.signature),
+            block_history::data_size.eq(record.data_size),
+            block_history::data_hash.eq(&record.data_hash),
+            block_history::action.eq(action),
+            block_history::archived_at.eq(timestamp()),
+            block_history::sig_scheme.eq(&record.sig_scheme),
+            block_history::data_encoding.eq(&record.data_encoding),
+            block_history::data_bytes.eq(&record.data_bytes),
+            block_history::delete_signature.eq(&record.delete_signature),
+            block_history::delete_challenge.eq(&record.delete_challenge),
+        )).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn list(conn: &DbConnection, public_key: &PublicKey, data_group: &String,
+                data_key: &String, limit: i64, before: Option<i32>) -> Result<(Vec<Self>, Option<i32>)> {
+        /* The newest versions go first, before is the id to continue from */
+        let mut query = block_history::table
+            .filter(block_history::public_key.eq(public_key.to_hex()))
+            .filter(block_history::data_group.eq(data_group))
+            .filter(block_history::data_key.eq(data_key))
+            .into_boxed();
+        if let Some(before) = before {
+            query = query.filter(block_history::id.lt(before));
+        }
+        let mut records: Vec<Self> = query.order(block_history::id.desc())
+                                          .limit(limit + 1).load(conn)?;
+        for record in records.iter_mut() {
+            if let Some(data_bytes) = &record.data_bytes {
+                record.data_block = DataEncoding::Base64.encode(data_bytes);
+            }
+        }
+        if records.len() as i64 > limit {
+            records.truncate(limit as usize);
+            let next_cursor = records.last().map(|r| r.id);
+            Ok((records, next_cursor))
+        } else {
+            Ok((records, None))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use crate::types::SignatureHex;
+    use crate::scheme::SigScheme;
+    use crate::version::VersionPolicy;
+    use crate::storage::Storage;
+    use crate::crypto::generate_secret;
+    use crate::db::testing::TestConnection;
+
+    fn public_key() -> PublicKey {
+        PublicKey::from_hex("ed25519:D75A980182B10AB7D54BFED3C964073A0EE172F3DAA62325AF021A68F707511A").unwrap()
+    }
+
+    fn signature(digit: &str) -> SignatureHex {
+        SignatureHex::try_from(digit.repeat(128)).unwrap()
+    }
+
+    fn insert(storage: &dyn Storage, data_key: &str, data_block: &str, data_encoding: DataEncoding) -> Block {
+        storage.insert(&public_key(), &"group".to_string(), &data_key.to_string(), &data_block.to_string(),
+                       data_encoding, &None, &"1".to_string(), VersionPolicy::None, &signature("0"),
+                       SigScheme::V1, &generate_secret()).unwrap()
+    }
+
+    fn update(storage: &dyn Storage, record: &Block, data_version: &str) -> Block {
+        storage.update(record, &"new block".to_string(), DataEncoding::Utf8, &None, &data_version.to_string(),
+                       VersionPolicy::None, &signature("0"), SigScheme::V2, &generate_secret()).unwrap()
+    }
+
+    fn list(conn: &DbConnection, data_key: &str, limit: i64, before: Option<i32>) -> (Vec<History>, Option<i32>) {
+        History::list(conn, &public_key(), &"group".to_string(), &data_key.to_string(), limit, before).unwrap()
+    }
+
+    #[test]
+    fn test_actions() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        let record = insert(storage, "key", "block", DataEncoding::Utf8);
+        let updated = update(storage, &record, "2");
+        storage.delete(&updated, &signature("1"), "CHALLENGE").unwrap();
+        let tombstone = storage.get(&public_key(), &"group".to_string(), &"key".to_string()).unwrap().unwrap();
+        update(storage, &tombstone, "3");
+
+        let (items, next_cursor) = list(&conn, "key", 10, None);
+        assert_eq!(next_cursor, None);
+        assert_eq!(items.iter().map(|h| h.action.as_str()).collect::<Vec<_>>(), vec!["revive", "delete", "update"]);
+        assert!(items.iter().all(|h| h.block_id == record.id));
+
+        let (revive, delete, update) = (&items[0], &items[1], &items[2]);
+        assert_eq!(update.data_block, "block");
+        assert_eq!(update.data_version, "1");
+        assert_eq!(update.sig_scheme, "v1");
+        assert_eq!(delete.data_block, "new block");
+        assert_eq!(delete.data_version, "2");
+        assert_eq!(delete.delete_signature, "");
+        // The tombstone keeps the proof of the deletion
+        assert_eq!(revive.data_block, "");
+        assert_eq!(revive.data_version, "2");
+        assert_eq!(revive.delete_signature, "1".repeat(128));
+        assert_eq!(revive.delete_challenge, "CHALLENGE");
+    }
+
+    #[test]
+    fn test_base64() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        let record = insert(storage, "key", "AQID", DataEncoding::Base64);
+        update(storage, &record, "2");
+
+        let (items, _) = list(&conn, "key", 10, None);
+        assert_eq!(items[0].data_block, "AQID");
+        assert_eq!(items[0].data_encoding, "base64");
+        assert_eq!(items[0].data_size, 3);
+    }
+
+    #[test]
+    fn test_pagination() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        let record = insert(storage, "key", "block", DataEncoding::Utf8);
+        let other = insert(storage, "other", "block", DataEncoding::Utf8);
+        for _ in 0..5 {
+            History::archive(&conn, &record, "update").unwrap();
+            History::archive(&conn, &other, "update").unwrap();
+        }
+
+        let (items, next_cursor) = list(&conn, "key", 2, None);
+        assert_eq!(items.len(), 2);
+        assert!(items[0].id > items[1].id);
+        assert_eq!(next_cursor, Some(items[1].id));
+
+        // The pages continue from the cursor without gaps and the other record is not mixed in
+        let mut ids: Vec<i32> = items.iter().map(|h| h.id).collect();
+        let mut cursor = next_cursor;
+        while let Some(before) = cursor {
+            let (items, next_cursor) = list(&conn, "key", 2, Some(before));
+            assert!(items.iter().all(|h| h.id < before && h.data_key == "key"));
+            ids.extend(items.iter().map(|h| h.id));
+            cursor = next_cursor;
+        }
+        assert_eq!(ids.len(), 5);
+        assert!(ids.windows(2).all(|pair| pair[0] > pair[1]));
+        assert_eq!(list(&conn, "key", 5, None).1, None);
+        assert!(list(&conn, "missing", 10, None).0.is_empty());
+    }
+}
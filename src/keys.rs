@@ -0,0 +1,343 @@
+use std::convert::TryFrom;
+use bigi_ecc::Point;
+use bigi_ecc::ecdsa::check_signature;
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+
+use crate::HASH_STORAGE_BITS;
+use crate::utils::*;
+use crate::types::SignatureHex;
+use crate::crypto::{SECP256K1, check_public_key, decompress_public_key};
+use crate::error::{Error, Result};
+
+const COMPRESSED_POINT_HEX_LENGTH: usize = HASH_STORAGE_BITS / 4 + 2;
+
+// Group orders in big-endian HEX, s is normalized to the lower half of them
+const SECP256K1_N_HEX: &str = "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141";
+const P256_N_HEX: &str = "FFFFFFFF00000000FFFFFFFFFFFFFFFFBCE6FAADA7179E84F3B9CAC2FC632551";
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    Secp256k1,
+    P256,
+    Ed25519,
+}
+
+
+/* Signature verification of one key type, msg is the signed 32-byte value
+   (a SHA-256 hash or the secret): ECDSA verifies it as a prehashed digest,
+   Ed25519 signs it as the message */
+pub trait Verifier {
+    type PublicKey;
+
+    fn parse_public_key(hex: &str) -> Result<Self::PublicKey>;
+    fn verify(public_key: &Self::PublicKey, msg: &[u8], signature: &SignatureHex) -> bool;
+}
+
+
+pub struct Secp256k1;
+pub struct P256;
+pub struct Ed25519;
+
+
+#[derive(Debug, Clone)]
+pub enum PublicKey {
+    Secp256k1(Point),
+    P256(p256::ecdsa::VerifyingKey),
+    Ed25519(ed25519_dalek::PublicKey),
+}
+
+
+impl KeyType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyType::Secp256k1 => "secp256k1",
+            KeyType::P256 => "p256",
+            KeyType::Ed25519 => "ed25519",
+        }
+    }
+
+    pub fn split_hex(hex: &str) -> Result<(Self, &str)> {
+        /* Keys are tagged as "p256:..." or "ed25519:...", untagged keys are Secp256k1 */
+        match hex.find(':') {
+            None => Ok((KeyType::Secp256k1, hex)),
+            Some(pos) => {
+                let key_type = match &hex[..pos] {
+                    "secp256k1" => KeyType::Secp256k1,
+                    "p256" => KeyType::P256,
+                    "ed25519" => KeyType::Ed25519,
+                    other => return Err(Error::BadHex(format!("unknown key type {:?}", other))),
+                };
+                Ok((key_type, &hex[pos + 1..]))
+            }
+        }
+    }
+}
+
+
+impl Verifier for Secp256k1 {
+    type PublicKey = Point;
+
+    fn parse_public_key(hex: &str) -> Result<Point> {
+        if hex.len() == COMPRESSED_POINT_HEX_LENGTH {
+            let (is_odd, x) = try_hex_to_point_compressed(hex)?;
+            decompress_public_key(is_odd, &x).ok_or(Error::BadPublicKey)
+        } else {
+            let point = try_hex_to_point(hex)?;
+            if check_public_key(&point) {
+                Ok(point)
+            } else {
+                Err(Error::BadPublicKey)
+            }
+        }
+    }
+
+    fn verify(public_key: &Point, msg: &[u8], signature: &SignatureHex) -> bool {
+        check_signature(&SECP256K1, public_key, msg, signature.pair())
+    }
+}
+
+
+impl Verifier for P256 {
+    type PublicKey = p256::ecdsa::VerifyingKey;
+
+    fn parse_public_key(hex: &str) -> Result<Self::PublicKey> {
+        // SEC1 encoding, either compressed or uncompressed (as WebCrypto exports it)
+        let bytes = try_hex_to_bytes(hex)?;
+        p256::ecdsa::VerifyingKey::from_sec1_bytes(&bytes).map_err(|_| Error::BadPublicKey)
+    }
+
+    fn verify(public_key: &Self::PublicKey, msg: &[u8], signature: &SignatureHex) -> bool {
+        match p256::ecdsa::Signature::from_slice(signature.bytes()) {
+            Ok(signature) => public_key.verify_prehash(msg, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+
+impl Verifier for Ed25519 {
+    type PublicKey = ed25519_dalek::PublicKey;
+
+    fn parse_public_key(hex: &str) -> Result<Self::PublicKey> {
+        check_hex(hex, Some(HASH_STORAGE_BITS / 4))?;
+        ed25519_dalek::PublicKey::from_bytes(&hex_to_bytes(hex)).map_err(|_| Error::BadPublicKey)
+    }
+
+    fn verify(public_key: &Self::PublicKey, msg: &[u8], signature: &SignatureHex) -> bool {
+        match ed25519_dalek::Signature::try_from(signature.bytes()) {
+            Ok(signature) => public_key.verify_strict(msg, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+
+impl PublicKey {
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let (key_type, hex) = KeyType::split_hex(hex)?;
+        match key_type {
+            KeyType::Secp256k1 => Secp256k1::parse_public_key(hex).map(PublicKey::Secp256k1),
+            KeyType::P256 => P256::parse_public_key(hex).map(PublicKey::P256),
+            KeyType::Ed25519 => Ed25519::parse_public_key(hex).map(PublicKey::Ed25519),
+        }
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            PublicKey::Secp256k1(_) => KeyType::Secp256k1,
+            PublicKey::P256(_) => KeyType::P256,
+            PublicKey::Ed25519(_) => KeyType::Ed25519,
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        /* The form stored in the database, Secp256k1 keys stay untagged
+           so the records saved before key types were added are found */
+        match self {
+            PublicKey::Secp256k1(point) => hex_from_point(point),
+            PublicKey::P256(key) => format!(
+                "p256:{}", hex_from_bytes(key.to_encoded_point(false).as_bytes())
+            ),
+            PublicKey::Ed25519(key) => format!("ed25519:{}", hex_from_bytes(key.as_bytes())),
+        }
+    }
+
+    pub fn to_compressed_hex(&self) -> String {
+        match self {
+            PublicKey::Secp256k1(point) => hex_from_point_compressed(point),
+            PublicKey::P256(key) => format!(
+                "p256:{}", hex_from_bytes(key.to_encoded_point(true).as_bytes())
+            ),
+            PublicKey::Ed25519(_) => self.to_hex(),
+        }
+    }
+
+    pub fn to_signed_bytes(&self) -> Vec<u8> {
+        /* The key as it is covered by signatures: the SEC1 compressed point of the curves
+           and the raw key of Ed25519, whatever form it has been sent or stored in */
+        match self {
+            PublicKey::Secp256k1(point) => hex_to_bytes(&hex_from_point_compressed(point)),
+            PublicKey::P256(key) => key.to_encoded_point(true).as_bytes().to_vec(),
+            PublicKey::Ed25519(key) => key.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn compress_stored_hex(hex: &str) -> String {
+        /* Compressed form of a key stored in the database, stored Secp256k1 keys
+           are not checked again, Ed25519 keys have no compressed form */
+        match KeyType::split_hex(hex) {
+            Ok((KeyType::Secp256k1, _)) => hex_from_point_compressed(&hex_to_point(hex)),
+            _ => PublicKey::from_hex(hex).map(|key| key.to_compressed_hex()).unwrap_or_else(|_| hex.to_string()),
+        }
+    }
+
+    pub fn canonical_signature(&self, signature: &SignatureHex) -> (SignatureHex, bool) {
+        /* Returns the low-S form of an ECDSA signature (s and n - s are both valid,
+           so only one of them is stored) and whether s has been high.
+           The signature must be already verified, so s is less than n. */
+        let size = HASH_STORAGE_BITS / 8;
+        match self {
+            PublicKey::Secp256k1(_) => {
+                let (r, s) = signature.pair();
+                let s = bigi_to_be_bytes(s);
+                match low_s(&s, &hex_to_bytes(SECP256K1_N_HEX)) {
+                    Some(s) => (SignatureHex::from_pair((*r, bigi_from_be_bytes(&s))), true),
+                    None => (SignatureHex::from_pair(*signature.pair()), false),
+                }
+            },
+            PublicKey::P256(_) => {
+                let (r, s) = signature.bytes().split_at(size);
+                match low_s(s, &hex_to_bytes(P256_N_HEX)) {
+                    Some(s) => (SignatureHex::from_be_bytes([r, &s].concat()), true),
+                    None => (signature.clone(), false),
+                }
+            },
+            // Strict verification of Ed25519 already rejects non-canonical S
+            PublicKey::Ed25519(_) => (signature.clone(), false),
+        }
+    }
+
+    pub fn verify(&self, msg: &[u8], signature: &SignatureHex) -> bool {
+        match self {
+            PublicKey::Secp256k1(key) => Secp256k1::verify(key, msg, signature),
+            PublicKey::P256(key) => P256::verify(key, msg, signature),
+            PublicKey::Ed25519(key) => Ed25519::verify(key, msg, signature),
+        }
+    }
+}
+
+
+fn low_s(s: &[u8], n: &[u8]) -> Option<Vec<u8>> {
+    /* n - s if s is in the upper half (s > n - s), otherwise None */
+    let negated = be_sub(n, s);
+    if s > &negated[..] {
+        Some(negated)
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{generate_private_key, build_public_key, sign_hash, generate_secret};
+
+    #[test]
+    fn test_split_hex() {
+        assert_eq!(KeyType::split_hex("ABCD").unwrap(), (KeyType::Secp256k1, "ABCD"));
+        assert_eq!(KeyType::split_hex("secp256k1:ABCD").unwrap(), (KeyType::Secp256k1, "ABCD"));
+        assert_eq!(KeyType::split_hex("p256:ABCD").unwrap(), (KeyType::P256, "ABCD"));
+        assert_eq!(KeyType::split_hex("ed25519:ABCD").unwrap(), (KeyType::Ed25519, "ABCD"));
+        assert!(KeyType::split_hex("rsa:ABCD").is_err());
+    }
+
+    #[test]
+    fn test_p256() {
+        use p256::ecdsa::signature::hazmat::PrehashSigner;
+
+        // The generator of P-256 is the public key of the private key 1
+        let public_key = PublicKey::from_hex(
+            "p256:036B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C296"
+        ).unwrap();
+        assert_eq!(public_key.key_type(), KeyType::P256);
+        assert_eq!(
+            public_key.to_hex(),
+            "p256:046B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C2964FE342E2FE1A7F9B8EE7EB4A7C0F9E162BCE33576B315ECECBB6406837BF51F5"
+        );
+        assert_eq!(
+            public_key.to_compressed_hex(),
+            "p256:036B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C296"
+        );
+        assert!(PublicKey::from_hex("p256:026B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C2").is_err());
+
+        let mut private_key = [0u8; 32];
+        private_key[31] = 1;
+        let signing_key = p256::ecdsa::SigningKey::from_slice(&private_key).unwrap();
+        let hash = generate_secret();
+        let signature: p256::ecdsa::Signature = signing_key.sign_prehash(&hash).unwrap();
+        let signature = SignatureHex::try_from(hex_from_bytes(&signature.to_bytes())).unwrap();
+        assert_eq!(public_key.verify(&hash, &signature), true);
+        assert_eq!(public_key.verify(&generate_secret(), &signature), false);
+
+        // Both s and n - s are valid, the canonical form is the same for them
+        let (low, _) = public_key.canonical_signature(&signature);
+        let s = &low.bytes()[32..];
+        let high_s = be_sub(&hex_to_bytes(P256_N_HEX), s);
+        let high = SignatureHex::from_be_bytes([&low.bytes()[..32], &high_s].concat());
+        assert_eq!(public_key.verify(&hash, &high), true);
+        let (canonical, is_high) = public_key.canonical_signature(&high);
+        assert_eq!(is_high, true);
+        assert_eq!(canonical.to_hex(), low.to_hex());
+        assert_eq!(public_key.canonical_signature(&low).1, false);
+
+        // DER form of the same signature
+        let der = p256::ecdsa::Signature::from_slice(low.bytes()).unwrap().to_der();
+        let from_der = SignatureHex::try_from(hex_from_bytes(der.as_bytes())).unwrap();
+        assert_eq!(from_der.to_hex(), low.to_hex());
+    }
+
+    #[test]
+    fn test_ed25519() {
+        // Test 1 of RFC 8032 (the empty message)
+        let public_key = PublicKey::from_hex(
+            "ed25519:D75A980182B10AB7D54BFED3C964073A0EE172F3DAA62325AF021A68F707511A"
+        ).unwrap();
+        assert_eq!(public_key.key_type(), KeyType::Ed25519);
+        let signature = SignatureHex::try_from(
+            "E5564300C360AC729086E2CC806E828A84877F1EB8E5D974D873E065224901555FB8821590A33BACC61E39701CF9B46BD25BF5F0595BBE24655141438E7A100B".to_string()
+        ).unwrap();
+        assert_eq!(public_key.verify(b"", &signature), true);
+        assert_eq!(public_key.verify(b"other", &signature), false);
+    }
+
+    #[test]
+    fn test_secp256k1() {
+        let private_key = generate_private_key();
+        let public_key = PublicKey::Secp256k1(build_public_key(&private_key));
+        let hash = generate_secret();
+        let signature = SignatureHex::from_pair(sign_hash(&private_key, &hash));
+        assert_eq!(public_key.verify(&hash, &signature), true);
+        assert_eq!(public_key.verify(&generate_secret(), &signature), false);
+    }
+
+    #[test]
+    fn test_secp256k1_high_s() {
+        let private_key = generate_private_key();
+        let public_key = PublicKey::Secp256k1(build_public_key(&private_key));
+        let hash = generate_secret();
+        let signature = SignatureHex::from_pair(sign_hash(&private_key, &hash));
+
+        // Both s and n - s are valid, the canonical form is the same for them
+        let (low, _) = public_key.canonical_signature(&signature);
+        let s = bigi_to_be_bytes(&low.pair().1);
+        let high_s = be_sub(&hex_to_bytes(SECP256K1_N_HEX), &s);
+        let high = SignatureHex::from_pair((low.pair().0, bigi_from_be_bytes(&high_s)));
+        assert_eq!(public_key.verify(&hash, &high), true);
+        let (canonical, is_high) = public_key.canonical_signature(&high);
+        assert_eq!(is_high, true);
+        assert_eq!(canonical.to_hex(), low.to_hex());
+        assert_eq!(public_key.canonical_signature(&low).1, false);
+    }
+}
@@ -3,45 +3,289 @@
 
 extern crate test;
 #[macro_use] extern crate rocket;
+#[macro_use] extern crate log;
 #[macro_use] extern crate rocket_contrib;
 #[macro_use] extern crate diesel;
 extern crate r2d2;
 extern crate r2d2_diesel;
+#[macro_use] extern crate lazy_static;
 
+use std::convert::TryFrom;
 use serde_derive::{Serialize, Deserialize};
-use rocket::http::Status;
+use rocket::State;
+use rocket::request::LenientForm;
 use rocket_contrib::json::{Json, JsonValue};
 
 const HASH_STORAGE_BITS: usize = 256;
+const MAX_BATCH_SIZE: usize = 100;
 
+mod error;
 mod utils;
+mod types;
+mod page;
+mod version;
+mod scheme;
+mod encoding;
+mod config;
 mod db;
 mod schema;
 mod block;
+mod history;
 mod crypto;
+mod keys;
+mod merkle;
+mod nonce;
+mod transparency;
+mod storage;
+mod memory;
+mod raw;
 
 use utils::*;
+use types::*;
 use crypto::*;
+use error::{Error, Result};
+use page::*;
+use version::VersionPolicy;
+use scheme::SigScheme;
+use encoding::DataEncoding;
+use config::Settings;
+use storage::{Storage, Store};
+use keys::PublicKey;
 use block::Block;
+use history::{History, MAX_HISTORY_PAGE_SIZE};
+use nonce::{Nonce, PURPOSE_INSERT, PURPOSE_AUTH};
+use transparency::{LogLeaf, TreeHead, TreeHeadCache, MAX_LOG_PAGE_SIZE};
+use raw::{RawBlock, RangeRequest, check_content_type};
 
 
 /* Data structures */
 
 #[derive(Serialize, Deserialize)]
 pub struct SaveInput {
-    pub public_key: String,
+    pub public_key: PublicKeyHex,
     pub data_group: String,
     pub data_key: String,
     pub data_block: String,
+    #[serde(default)]
+    pub data_encoding: DataEncoding,
+    #[serde(default)]
+    pub content_type: Option<String>,
     pub data_version: String,
-    pub signature: String,
-    pub secret_signature: String,
+    pub signature: SignatureHex,
+    #[serde(default)]
+    pub sig_scheme: SigScheme,
+    #[serde(flatten)]
+    pub auth: Authorization,
+    #[serde(default)]
+    pub version_policy: Option<VersionPolicy>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_signature")]
+    pub nonce_signature: Option<SignatureHex>,
 }
 
 
 #[derive(Serialize, Deserialize)]
 pub struct DeleteInput {
-    pub secret_signature: String,
+    #[serde(flatten)]
+    pub auth: Authorization,
+}
+
+
+// A record given in full (data_block, data_version, signature) or only referenced
+// by public_key, data_group and data_key to check the stored one
+#[derive(Serialize, Deserialize)]
+pub struct VerifyInput {
+    pub public_key: PublicKeyHex,
+    pub data_group: String,
+    pub data_key: String,
+    #[serde(default)]
+    pub data_block: Option<String>,
+    #[serde(default)]
+    pub data_encoding: DataEncoding,
+    #[serde(default)]
+    pub data_version: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_signature")]
+    pub signature: Option<SignatureHex>,
+    #[serde(default)]
+    pub sig_scheme: Option<SigScheme>,
+}
+
+
+// Either secret_signature (signature of the public secret of the record)
+// or challenge with challenge_signature (see /auth/challenge)
+#[derive(Serialize, Deserialize)]
+pub struct Authorization {
+    #[serde(default, deserialize_with = "deserialize_optional_signature")]
+    pub secret_signature: Option<SignatureHex>,
+    #[serde(default)]
+    pub challenge: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_signature")]
+    pub challenge_signature: Option<SignatureHex>,
+}
+
+
+/* Logic */
+
+fn check_insert_nonce(storage: &dyn Storage, settings: &Settings, input: &SaveInput,
+                      data_bytes: &[u8], required: bool) -> Result<()> {
+    /* A new record (or a record over its tombstone) may be bound to a nonce
+       issued by /nonce, so a captured request cannot be replayed later */
+    let value = match &input.nonce {
+        Some(value) => value,
+        None if required || settings.require_nonce => {
+            return Err(Error::BadNonce("nonce is required to insert a record".to_string()));
+        },
+        None => return Ok(()),
+    };
+    let nonce = try_hex_to_bytes(value)?;
+    let nonce_signature = input.nonce_signature.as_ref().ok_or(
+        Error::BadNonce("nonce_signature is required with nonce".to_string())
+    )?;
+    if !check_nonce_signature(input.public_key.key(), &nonce, data_bytes, nonce_signature) {
+        return Err(Error::BadNonce("nonce signature is invalid".to_string()));
+    }
+    if !storage.consume_nonce(&value.to_uppercase(), PURPOSE_INSERT)? {
+        return Err(Error::BadNonce("nonce is unknown, expired or already used".to_string()));
+    }
+    Ok(())
+}
+
+
+fn canonical_signature(settings: &Settings, public_key: &PublicKey, signature: &SignatureHex) -> Result<SignatureHex> {
+    /* Low-S form of a verified signature, the one that is stored */
+    let (canonical, is_high_s) = public_key.canonical_signature(signature);
+    if is_high_s && settings.reject_high_s {
+        return Err(Error::HighS);
+    }
+    Ok(canonical)
+}
+
+
+fn authorize(storage: &dyn Storage, settings: &Settings, auth: &Authorization, public_key: &PublicKey,
+             operation: &str, record: &Block, data_hash: &[u8]) -> Result<SignatureHex> {
+    /* Checks the right of the owner to change the record,
+       returns the canonical form of the signature that has been checked */
+    match &auth.challenge {
+        Some(challenge) => {
+            let challenge_bytes = try_hex_to_bytes(challenge)?;
+            let challenge_signature = auth.challenge_signature.as_ref().ok_or(
+                Error::BadChallenge("challenge_signature is required with challenge".to_string())
+            )?;
+            if !check_challenge_signature(public_key, &challenge_bytes, operation, &record.data_group,
+                                          &record.data_key, data_hash, challenge_signature) {
+                return Err(Error::BadChallenge("challenge signature is invalid".to_string()));
+            }
+            if !storage.consume_nonce(&challenge.to_uppercase(), PURPOSE_AUTH)? {
+                return Err(Error::BadChallenge("challenge is unknown, expired or already used".to_string()));
+            }
+            canonical_signature(settings, public_key, challenge_signature)
+        },
+        None if !settings.secret_auth => {
+            Err(Error::BadChallenge("challenge is required, secret_signature is disabled".to_string()))
+        },
+        None => {
+            let secret_signature = auth.secret_signature.as_ref().ok_or(Error::BadSecretSignature)?;
+            let secret = hex_to_bytes(&record.secret);
+            if !check_secret_signature(public_key, &secret, secret_signature) {
+                // The secret has been consumed by a concurrent request that has won
+                let previous_secret = hex_to_bytes(&record.previous_secret);
+                if !previous_secret.is_empty() && check_secret_signature(public_key, &previous_secret, secret_signature) {
+                    return Err(Error::Conflict);
+                }
+                return Err(Error::BadSecretSignature);
+            }
+            canonical_signature(settings, public_key, secret_signature)
+        }
+    }
+}
+
+
+fn version_policy(settings: &Settings, input: &SaveInput, record: Option<&Block>) -> Result<VersionPolicy> {
+    /* The policy of the instance applies to everybody. Otherwise the policy of the request
+       is stored in the record and kept on later saves, so a replayed or forged request
+       cannot drop it by omitting the field */
+    if settings.version_policy != VersionPolicy::None {
+        return Ok(settings.version_policy);
+    }
+    let stored = match record {
+        Some(record) => record.version_policy.parse().map_err(Error::BadVersion)?,
+        None => VersionPolicy::None,
+    };
+    match (stored, input.version_policy) {
+        (VersionPolicy::None, requested) => Ok(requested.unwrap_or(VersionPolicy::None)),
+        (stored, Some(requested)) if requested != stored => Err(Error::BadVersion(
+            format!("the record has version policy {}, it cannot be changed", stored.name())
+        )),
+        (stored, _) => Ok(stored),
+    }
+}
+
+
+fn check_save_input(settings: &Settings, input: &SaveInput) -> Result<SignatureHex> {
+    /* The checks that do not need the database, so they can run outside the transaction
+       (and in parallel for a batch), returns the canonical data signature */
+    let public_key = input.public_key.key();
+
+    version_policy(settings, input, None)?.parse(&input.data_version)?;
+
+    // The size and the signature are of the decoded bytes
+    let data_bytes = input.data_encoding.decode(&input.data_block)?;
+    if !check_data_block_size(&data_bytes) {
+        return Err(Error::BlockTooLarge);
+    }
+
+    if input.sig_scheme < settings.min_sig_scheme {
+        return Err(Error::BadSigScheme(format!("{} is required at least", settings.min_sig_scheme.name())));
+    }
+
+    if !input.sig_scheme.check_data_signature(&public_key, &input.data_group, &input.data_key,
+                                              &data_bytes, &input.data_version, &input.signature) {
+        return Err(Error::BadSignature);
+    }
+    canonical_signature(settings, &public_key, &input.signature)
+}
+
+
+fn save_record(storage: &dyn Storage, settings: &Settings, input: &SaveInput, signature: &SignatureHex) -> Result<Block> {
+    /* The input must be checked by check_save_input, the signature is its result */
+    let public_key = input.public_key.key();
+    let data_group = &input.data_group;
+    let data_key = &input.data_key;
+    let data_block = &input.data_block;
+    let data_bytes = input.data_encoding.decode(data_block)?;
+    let content_type = input.content_type.as_deref().map(check_content_type).transpose()?;
+    let data_version = &input.data_version;
+    let record = storage.get(&public_key, &data_group, &data_key)?;
+    let version_policy = version_policy(settings, input, record.as_ref())?;
+
+    match record {
+        Some(record) if record.deleted => {
+            // A replayed save of the deleted record must not bring it back,
+            // so the version must grow even if there is no version policy
+            // A replayed save of the deleted record must not bring it back: the version must grow
+            // under a version policy, otherwise (or if the last version does not follow it) a nonce is required
+            let versioned = version_policy.parse(&record.data_version).map_or(false, |v| !v.is_empty());
+            if versioned {
+                version_policy.check_newer(data_version, &record.data_version)?;
+            }
+            check_insert_nonce(storage, settings, input, &data_bytes, !versioned)?;
+            storage.update(&record, &data_block, input.data_encoding, &content_type, &data_version,
+                           version_policy, &signature, input.sig_scheme, &generate_secret())
+        },
+        Some(record) => {
+            authorize(storage, settings, &input.auth, &public_key, "save", &record, &hash_data_block(&data_bytes))?;
+            version_policy.check_newer(data_version, &record.data_version)?;
+            storage.update(&record, &data_block, input.data_encoding, &content_type, &data_version,
+                           version_policy, &signature, input.sig_scheme, &generate_secret())
+        },
+        None => {
+            check_insert_nonce(storage, settings, input, &data_bytes, false)?;
+            storage.insert(&public_key, &data_group, &data_key, &data_block, input.data_encoding,
+                           &content_type, &data_version, version_policy, &signature, input.sig_scheme,
+                           &generate_secret())
+        }
+    }
 }
 
 
@@ -54,124 +298,475 @@ fn version() -> JsonValue {
 
 
 #[get("/check/<public_key_hex>")]
-fn check(public_key_hex: String, conn: db::Connection) -> Result<Json<JsonValue>, Status> {
-    let public_key = hex_to_point(&public_key_hex);
-    let exists = Block::check(&conn, &public_key);
+fn check(public_key_hex: Result<PublicKeyHex>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = public_key_hex?.into_key();
+    let exists = storage.check(&public_key)?;
     Ok(Json(json!({"exists": exists})))
 }
 
 
 #[get("/groups/<public_key_hex>")]
-fn groups(public_key_hex: String, conn: db::Connection) -> Result<Json<JsonValue>, Status> {
-    let public_key = hex_to_point(&public_key_hex);
-    let records = Block::groups(&conn, &public_key);
+fn groups(public_key_hex: Result<PublicKeyHex>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = public_key_hex?.into_key();
+    let records = storage.groups(&public_key)?;
     Ok(Json(json!(records)))
 }
 
 
-#[get("/keys/<public_key_hex>/<data_group>")]
-fn keys(public_key_hex: String, data_group: String, conn: db::Connection) -> Result<Json<JsonValue>, Status> {
-    let public_key = hex_to_point(&public_key_hex);
-    let records = Block::keys(&conn, &public_key, &data_group);
-    Ok(Json(json!(records)))
+#[get("/keys/<public_key_hex>/<data_group>?<page..>")]
+fn keys(public_key_hex: Result<PublicKeyHex>, data_group: String, page: LenientForm<PageQuery>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = public_key_hex?.into_key();
+    let page = page.into_inner().into_page(MAX_KEYS_PAGE_SIZE)?;
+    let (records, next_cursor) = storage.keys(&public_key, &data_group, &page)?;
+    Ok(Json(json!({"items": records, "next_cursor": next_cursor})))
 }
 
 
-#[get("/list/<public_key_hex>/<data_group>")]
-fn list(public_key_hex: String, data_group: String, conn: db::Connection) -> Result<Json<JsonValue>, Status> {
-    let public_key = hex_to_point(&public_key_hex);
-    let records = Block::list(&conn, &public_key, &data_group);
-    Ok(Json(json!(records)))
+#[get("/list/<public_key_hex>/<data_group>?<compressed>&<page..>")]
+fn list(public_key_hex: Result<PublicKeyHex>, data_group: String, compressed: Option<bool>, page: LenientForm<PageQuery>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = public_key_hex?.into_key();
+    let page = page.into_inner().into_page(MAX_LIST_PAGE_SIZE)?;
+    let (mut records, next_cursor) = storage.list(&public_key, &data_group, &page)?;
+    if compressed.unwrap_or(false) {
+        records.iter_mut().for_each(Block::compress_public_key);
+    }
+    Ok(Json(json!({"items": records, "next_cursor": next_cursor})))
+}
+
+
+#[get("/meta/<public_key_hex>/<data_group>?<page..>")]
+fn meta(public_key_hex: Result<PublicKeyHex>, data_group: String, page: LenientForm<PageQuery>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = public_key_hex?.into_key();
+    let page = page.into_inner().into_page(MAX_KEYS_PAGE_SIZE)?;
+    let (records, next_cursor) = storage.meta(&public_key, &data_group, &page)?;
+    Ok(Json(json!({"items": records, "next_cursor": next_cursor})))
 }
 
 
-#[get("/get/<public_key_hex>/<data_group>/<data_key>")]
-fn get(public_key_hex: String, data_group: String, data_key: String, conn: db::Connection) -> Result<Json<Block>, Status> {
-    let public_key = hex_to_point(&public_key_hex);
-    match Block::get(&conn, &public_key, &data_group, &data_key) {
-        Some(record) => Ok(Json(record)),
-        None => Err(Status::NotFound)
+#[get("/get/<public_key_hex>/<data_group>/<data_key>?<compressed>")]
+fn get(public_key_hex: Result<PublicKeyHex>, data_group: String, data_key: String, compressed: Option<bool>, storage: Store) -> Result<Json<Block>> {
+    let public_key = public_key_hex?.into_key();
+    match storage.get(&public_key, &data_group, &data_key)? {
+        Some(mut record) if !record.deleted => {
+            if compressed.unwrap_or(false) {
+                record.compress_public_key();
+            }
+            Ok(Json(record))
+        },
+        _ => Err(Error::NotFound)
     }
 }
 
 
-#[post("/save", format = "application/json", data = "<input>")]
-fn save(input: Json<SaveInput>, conn: db::Connection) -> Result<Json<Block>, Status> {
-    let public_key = hex_to_point(&input.public_key);
-    let data_group = &input.data_group;
-    let data_key = &input.data_key;
-    let data_block = &input.data_block;
-    let data_version = &input.data_version;
-    let signature = hex_to_bigi_pair(&input.signature);
+#[get("/raw/<public_key_hex>/<data_group>/<data_key>")]
+fn raw(public_key_hex: Result<PublicKeyHex>, data_group: String, data_key: String, range: RangeRequest, storage: Store) -> Result<RawBlock> {
+    let public_key = public_key_hex?.into_key();
+    match storage.get(&public_key, &data_group, &data_key)? {
+        Some(record) if !record.deleted => Ok(RawBlock::new(record, &range)),
+        _ => Err(Error::NotFound)
+    }
+}
 
-    let secret_signature_option = if !input.secret_signature.is_empty() {
-        Some(hex_to_bigi_pair(&input.secret_signature))
-    } else {
-        None
+
+#[get("/history/<public_key_hex>/<data_group>/<data_key>?<limit>&<cursor>&<compressed>")]
+fn history(public_key_hex: Result<PublicKeyHex>, data_group: String, data_key: String, limit: Option<String>, cursor: Option<String>, compressed: Option<bool>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = public_key_hex?.into_key();
+    let cursor = parse_query("cursor", cursor.as_deref())?;
+    let limit = match parse_query::<i64>("limit", limit.as_deref())? {
+        Some(limit) if limit < 1 => return Err(Error::BadQuery("limit must be positive".to_string())),
+        Some(limit) => limit.min(MAX_HISTORY_PAGE_SIZE),
+        None => MAX_HISTORY_PAGE_SIZE,
     };
+    let (mut records, next_cursor) = storage.history(&public_key, &data_group, &data_key, limit, cursor)?;
+    if compressed.unwrap_or(false) {
+        records.iter_mut().for_each(History::compress_public_key);
+    }
+    Ok(Json(json!({"items": records, "next_cursor": next_cursor})))
+}
 
-    if check_data_block_size(&data_block) {
-        if check_data_signature(&public_key, &data_group, &data_key, &data_block, &data_version, &signature) {
-            match Block::get(&conn, &public_key, &data_group, &data_key) {
-                Some(record) => {
-                    match secret_signature_option {
-                        Some(secret_signature) => {
-                            let secret = hex_to_bytes(&record.secret);
-                            if check_secret_signature(&public_key, &secret, &secret_signature) {
-                                let secret = generate_secret();
-                                Block::update(&conn, record.id, &data_block, &data_version, &signature, &secret);
-                                let new_record = Block::get(&conn, &public_key, &data_group, &data_key).unwrap();
-                                Ok(Json(new_record))
-                            } else {
-                                Err(Status::Forbidden)
-                            }
-                        },
-                        None => {
-                            Err(Status::Forbidden)
-                        }
-                    }
-                },
-                None => {
-                    let secret = generate_secret();
-                    Block::insert(&conn, &public_key, &data_group, &data_key, &data_block, &data_version, &signature, &secret);
-                    let new_record = Block::get(&conn, &public_key, &data_group, &data_key).unwrap();
-                    Ok(Json(new_record))
-                }
+
+#[post("/save?<compressed>", format = "application/json", data = "<input>")]
+fn save(input: Json<SaveInput>, compressed: Option<bool>, settings: State<Settings>, storage: Store) -> Result<Json<Block>> {
+    let signature = check_save_input(&settings, &input)?;
+    let mut new_record = storage.write_transaction(|| save_record(&*storage, &settings, &input, &signature))?;
+    if compressed.unwrap_or(false) {
+        new_record.compress_public_key();
+    }
+    Ok(Json(new_record))
+}
+
+
+#[post("/batch/save?<compressed>", format = "application/json", data = "<inputs>")]
+fn batch_save(inputs: Json<Vec<SaveInput>>, compressed: Option<bool>, settings: State<Settings>, storage: Store) -> Result<Json<Vec<Block>>> {
+    if inputs.len() > MAX_BATCH_SIZE {
+        return Err(Error::BadQuery(format!("batch is limited by {} records", MAX_BATCH_SIZE)));
+    }
+
+    // Signatures are verified in parallel before the database is locked
+    let mut signatures = Vec::with_capacity(inputs.len());
+    let mut errors = Vec::new();
+    for (index, result) in par_map(&inputs, |input| check_save_input(&settings, input)).into_iter().enumerate() {
+        match result {
+            Ok(signature) => signatures.push(signature),
+            Err(err) => errors.push((index, err)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Error::Batch(errors));
+    }
+
+    let mut new_records = storage.write_transaction(|| {
+        let mut new_records = Vec::with_capacity(inputs.len());
+        let mut errors = Vec::new();
+        for (index, (input, signature)) in inputs.iter().zip(&signatures).enumerate() {
+            match save_record(&*storage, &settings, input, signature) {
+                Ok(record) => new_records.push(record),
+                Err(err) => errors.push((index, err)),
             }
+        }
+        if errors.is_empty() {
+            Ok(new_records)
         } else {
-            Err(Status::Forbidden)
+            Err(Error::Batch(errors))
         }
-    } else {
-        Err(Status::BadRequest)
+    })?;
+
+    if compressed.unwrap_or(false) {
+        new_records.iter_mut().for_each(Block::compress_public_key);
     }
+    Ok(Json(new_records))
 }
 
 
-#[post("/delete/<public_key_hex>/<data_group>/<data_key>", format = "application/json", data = "<input>")]
-fn delete(public_key_hex: String, data_group: String, data_key: String, input: Json<DeleteInput>, conn: db::Connection) -> Result<Json<JsonValue>, Status> {
-    let public_key = hex_to_point(&public_key_hex);
-    let secret_signature = hex_to_bigi_pair(&input.secret_signature);
+#[post("/verify", format = "application/json", data = "<input>")]
+fn verify(input: Json<VerifyInput>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = input.public_key.key();
+    let (data_bytes, data_version, signature, sig_scheme) = match (&input.data_block, &input.data_version, &input.signature) {
+        (Some(data_block), Some(data_version), Some(signature)) => (
+            input.data_encoding.decode(data_block)?, data_version.clone(), signature.clone(),
+            input.sig_scheme.unwrap_or_default()
+        ),
+        (None, None, None) => match storage.get(&public_key, &input.data_group, &input.data_key)? {
+            Some(record) if !record.deleted => {
+                // A scheme in the input overrides the stored one
+                let sig_scheme = match input.sig_scheme {
+                    Some(sig_scheme) => sig_scheme,
+                    None => record.sig_scheme.parse().map_err(Error::BadSigScheme)?,
+                };
+                let data_bytes = record.raw_data().to_vec();
+                let signature = SignatureHex::try_from(record.signature)?;
+                (data_bytes, record.data_version, signature, sig_scheme)
+            },
+            _ => return Err(Error::NotFound),
+        },
+        _ => return Err(Error::BadQuery("data_block, data_version and signature must be given together".to_string())),
+    };
+    let valid = sig_scheme.check_data_signature(public_key, &input.data_group, &input.data_key,
+                                                &data_bytes, &data_version, &signature);
+    Ok(Json(json!({
+        "valid": valid,
+        "sig_scheme": sig_scheme,
+        "key_type": public_key.key_type().name(),
+    })))
+}
 
-    match Block::get(&conn, &public_key, &data_group, &data_key) {
-        Some(record) => {
-            let secret = hex_to_bytes(&record.secret);
-            if check_secret_signature(&public_key, &secret, &secret_signature) {
-                Block::delete(&conn, record.id);
+
+#[post("/nonce")]
+fn nonce(storage: Store) -> Result<Json<Nonce>> {
+    Ok(Json(storage.issue_nonce(PURPOSE_INSERT)?))
+}
+
+
+#[post("/auth/challenge")]
+fn auth_challenge(storage: Store) -> Result<Json<JsonValue>> {
+    let challenge = storage.issue_nonce(PURPOSE_AUTH)?;
+    Ok(Json(json!({
+        "challenge": challenge.value,
+        "created_at": challenge.created_at,
+        "expires_at": challenge.expires_at,
+    })))
+}
+
+
+#[post("/delete/<public_key_hex>/<data_group>/<data_key>", format = "application/json", data = "<input>")]
+fn delete(public_key_hex: Result<PublicKeyHex>, data_group: String, data_key: String, input: Json<DeleteInput>, settings: State<Settings>, storage: Store) -> Result<Json<JsonValue>> {
+    let public_key = public_key_hex?.into_key();
+
+    storage.write_transaction(|| {
+        match storage.get(&public_key, &data_group, &data_key)? {
+            Some(record) if !record.deleted => {
+                let delete_signature = authorize(&*storage, &settings, &input.auth, &public_key, "delete", &record, &[])?;
+                let delete_challenge = input.auth.challenge.as_ref().map(|c| c.to_uppercase()).unwrap_or_default();
+                storage.delete(&record, &delete_signature, &delete_challenge)?;
                 Ok(Json(json!({"success": true})))
-            } else {
-                Err(Status::Forbidden)
-            }
-        },
-        None => Err(Status::NotFound)
+            },
+            _ => Err(Error::NotFound)
+        }
+    })
+}
+
+
+#[get("/log/head")]
+fn log_head(settings: State<Settings>, cache: State<TreeHeadCache>, storage: Store) -> Result<Json<TreeHead>> {
+    Ok(Json(cache.get(&*storage, &settings.log_private_key, &settings.log_public_key)?))
+}
+
+
+#[get("/log/leaves?<start>&<limit>")]
+fn log_leaves(start: Option<String>, limit: Option<String>, storage: Store) -> Result<Json<JsonValue>> {
+    let start = parse_query("start", start.as_deref())?.unwrap_or(0);
+    if start < 0 {
+        return Err(Error::BadQuery("start must not be negative".to_string()));
+    }
+    let limit = match parse_query::<i64>("limit", limit.as_deref())? {
+        Some(limit) if limit < 1 => return Err(Error::BadQuery("limit must be positive".to_string())),
+        Some(limit) => limit.min(MAX_LOG_PAGE_SIZE),
+        None => MAX_LOG_PAGE_SIZE,
+    };
+    let records = storage.log_leaves(start, limit)?;
+    Ok(Json(json!({"items": records, "tree_size": storage.log_size()?})))
+}
+
+
+#[get("/log/record/<public_key_hex>/<data_group>/<data_key>?<compressed>")]
+fn log_record(public_key_hex: Result<PublicKeyHex>, data_group: String, data_key: String, compressed: Option<bool>, storage: Store) -> Result<Json<Vec<LogLeaf>>> {
+    let public_key = public_key_hex?.into_key();
+    let mut records = storage.log_record(&public_key, &data_group, &data_key)?;
+    if compressed.unwrap_or(false) {
+        records.iter_mut().for_each(LogLeaf::compress_public_key);
+    }
+    Ok(Json(records))
+}
+
+
+#[get("/log/inclusion/<leaf_index>?<tree_size>")]
+fn log_inclusion(leaf_index: i64, tree_size: Option<String>, storage: Store) -> Result<Json<JsonValue>> {
+    let tree_size = LogLeaf::checked_size(storage.log_size()?, parse_query("tree_size", tree_size.as_deref())?)?;
+    if leaf_index < 0 || leaf_index >= tree_size {
+        return Err(Error::NotFound);
     }
+    let tree = storage.log_tree()?;
+    let proof = merkle::inclusion_proof(&*tree, leaf_index as u64, tree_size as u64)?;
+    Ok(Json(json!({
+        "leaf_index": leaf_index,
+        "tree_size": tree_size,
+        "leaf_hash": hex_from_bytes(&tree.node(0, leaf_index as u64)?),
+        "root_hash": hex_from_bytes(&merkle::root(&*tree, 0, tree_size as u64)?),
+        "audit_path": proof.iter().map(|h| hex_from_bytes(h)).collect::<Vec<String>>(),
+    })))
+}
+
+
+#[get("/log/consistency/<first>/<second>")]
+fn log_consistency(first: i64, second: i64, storage: Store) -> Result<Json<JsonValue>> {
+    let second = LogLeaf::checked_size(storage.log_size()?, Some(second))?;
+    if first < 1 || first > second {
+        return Err(Error::BadQuery(format!("first must be between 1 and {}", second)));
+    }
+    let tree = storage.log_tree()?;
+    let proof = merkle::consistency_proof(&*tree, first as u64, second as u64)?;
+    Ok(Json(json!({
+        "first": first,
+        "second": second,
+        "first_root_hash": hex_from_bytes(&merkle::root(&*tree, 0, first as u64)?),
+        "second_root_hash": hex_from_bytes(&merkle::root(&*tree, 0, second as u64)?),
+        "consistency_path": proof.iter().map(|h| hex_from_bytes(h)).collect::<Vec<String>>(),
+    })))
+}
+
+
+/* Catchers */
+
+#[catch(400)]
+fn bad_request() -> Error {
+    Error::BadInput
+}
+
+
+#[catch(422)]
+fn unprocessable_entity() -> Error {
+    Error::BadInput
 }
 
 
 fn main() {
     rocket::ignite()
-        .manage(db::connect())
+        .attach(Settings::fairing())
+        .attach(Store::fairing())
+        .manage(TreeHeadCache::default())
         .mount("/", routes![
-            version, check, groups, keys, list, get, save, delete,
+            version, check, groups, keys, list, meta, get, raw, history, save, batch_save, verify, nonce, auth_challenge, delete,
+            log_head, log_leaves, log_record, log_inclusion, log_consistency,
         ])
+        .register(catchers![bad_request, unprocessable_entity])
         .launch();
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Status;
+    use sha2::{Sha256, Digest};
+    use p256::ecdsa::signature::hazmat::PrehashSigner;
+    use crate::memory::MemoryStorage;
+
+    // P-256 keys do not need the Secp256k1 schema, the private key is 1
+    const PUBLIC_KEY: &str = "p256:036B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C296";
+
+    fn settings() -> Settings {
+        Settings {
+            version_policy: VersionPolicy::None,
+            log_private_key: hex_to_bigi(&"0".repeat(64)),
+            log_public_key: hex_to_point(&"0".repeat(128)),
+            require_nonce: false,
+            secret_auth: true,
+            min_sig_scheme: SigScheme::V1,
+            reject_high_s: false,
+        }
+    }
+
+    fn sign(hash: &[u8]) -> String {
+        let mut private_key = [0u8; 32];
+        private_key[31] = 1;
+        let signing_key = p256::ecdsa::SigningKey::from_slice(&private_key).unwrap();
+        let signature: p256::ecdsa::Signature = signing_key.sign_prehash(hash).unwrap();
+        hex_from_bytes(&signature.to_bytes())
+    }
+
+    fn save(storage: &MemoryStorage, data_version: &str, fields: serde_json::Value) -> Result<Block> {
+        /* Saves "block" of group/key, fields are added to the input */
+        let storage: &dyn Storage = storage;
+        let hash = Sha256::new().chain("group").chain("key").chain("block").chain(data_version).result();
+        let mut input = serde_json::json!({
+            "public_key": PUBLIC_KEY, "data_group": "group", "data_key": "key", "data_block": "block",
+            "data_version": data_version, "signature": sign(&hash),
+        });
+        input.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        let input: SaveInput = serde_json::from_value(input).unwrap();
+        let settings = settings();
+        let signature = check_save_input(&settings, &input)?;
+        storage.write_transaction(|| save_record(storage, &settings, &input, &signature))
+    }
+
+    #[test]
+    fn test_save_consumed_secret() {
+        let storage = MemoryStorage::new();
+        let record = save(&storage, "1", serde_json::json!({})).unwrap();
+        let secret_signature = sign(&hex_to_bytes(&record.secret));
+
+        // The second request with the same secret has lost the race
+        save(&storage, "2", serde_json::json!({"secret_signature": secret_signature})).unwrap();
+        let err = save(&storage, "3", serde_json::json!({"secret_signature": secret_signature})).unwrap_err();
+        assert!(matches!(err, Error::Conflict));
+        assert_eq!(err.status(), Status::Conflict);
+
+        let err = save(&storage, "3", serde_json::json!({"secret_signature": sign(&generate_secret())})).unwrap_err();
+        assert_eq!(err.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn test_save_stored_version_policy() {
+        let storage = MemoryStorage::new();
+        let record = save(&storage, "5", serde_json::json!({"version_policy": "integer"})).unwrap();
+        assert_eq!(record.version_policy, "integer");
+
+        // A request without the field keeps the policy of the record
+        let secret_signature = sign(&hex_to_bytes(&record.secret));
+        let err = save(&storage, "4", serde_json::json!({"secret_signature": secret_signature})).unwrap_err();
+        assert!(matches!(err, Error::StaleVersion));
+
+        let fields = serde_json::json!({"secret_signature": secret_signature, "version_policy": "none"});
+        let err = save(&storage, "6", fields).unwrap_err();
+        assert!(matches!(err, Error::BadVersion(_)));
+
+        let record = save(&storage, "6", serde_json::json!({"secret_signature": secret_signature})).unwrap();
+        assert_eq!(record.version_policy, "integer");
+    }
+
+    fn nonce_fields(storage: &MemoryStorage) -> serde_json::Value {
+        let nonce = storage.issue_nonce(PURPOSE_INSERT).unwrap();
+        let hash = Sha256::new().chain(hex_to_bytes(&nonce.value)).chain(hash_data_block(b"block")).result();
+        serde_json::json!({"nonce": nonce.value, "nonce_signature": sign(&hash)})
+    }
+
+    fn delete(storage: &MemoryStorage, record: &Block) {
+        let delete_signature = SignatureHex::try_from(sign(&generate_secret())).unwrap();
+        storage.delete(record, &delete_signature, "").unwrap();
+    }
+
+    #[test]
+    fn test_save_over_tombstone() {
+        // Without a version policy any version can be saved again, but only with a nonce
+        let storage = MemoryStorage::new();
+        let record = save(&storage, "1.0.4", serde_json::json!({})).unwrap();
+        delete(&storage, &record);
+        let err = save(&storage, "1.0.4", serde_json::json!({})).unwrap_err();
+        assert!(matches!(err, Error::BadNonce(_)));
+        let record = save(&storage, "", nonce_fields(&storage)).unwrap();
+        assert_eq!(record.deleted, false);
+        assert_eq!(record.data_version, "");
+
+        // With a version policy the version must grow instead
+        let storage = MemoryStorage::new();
+        let record = save(&storage, "3", serde_json::json!({"version_policy": "integer"})).unwrap();
+        delete(&storage, &record);
+        let err = save(&storage, "3", nonce_fields(&storage)).unwrap_err();
+        assert!(matches!(err, Error::StaleVersion));
+        let record = save(&storage, "4", serde_json::json!({})).unwrap();
+        assert_eq!(record.deleted, false);
+    }
+
+    fn verify_fields(storage: &MemoryStorage, fields: serde_json::Value) -> Result<serde_json::Value> {
+        let mut input = serde_json::json!({"public_key": PUBLIC_KEY, "data_group": "group", "data_key": "key"});
+        input.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        let input: VerifyInput = serde_json::from_value(input).unwrap();
+        verify(Json(input), Store::Memory(storage)).map(|response| response.into_inner().0)
+    }
+
+    #[test]
+    fn test_verify_submitted_record() {
+        let storage = MemoryStorage::new();
+        let hash = Sha256::new().chain("group").chain("key").chain("block").chain("1").result();
+        let fields = serde_json::json!({"data_block": "block", "data_version": "1", "signature": sign(&hash)});
+
+        // The scheme is v1 if it is not given
+        let response = verify_fields(&storage, fields.clone()).unwrap();
+        assert_eq!(response, serde_json::json!({"valid": true, "sig_scheme": "v1", "key_type": "p256"}));
+        let mut v2_fields = fields.clone();
+        v2_fields["sig_scheme"] = serde_json::json!("v2");
+        let response = verify_fields(&storage, v2_fields).unwrap();
+        assert_eq!(response, serde_json::json!({"valid": false, "sig_scheme": "v2", "key_type": "p256"}));
+        let mut changed_fields = fields.clone();
+        changed_fields["data_version"] = serde_json::json!("2");
+        assert_eq!(verify_fields(&storage, changed_fields).unwrap()["valid"], false);
+
+        let err = verify_fields(&storage, serde_json::json!({"data_block": "block", "data_version": "1"})).unwrap_err();
+        assert!(matches!(err, Error::BadQuery(_)));
+    }
+
+    #[test]
+    fn test_verify_stored_record() {
+        let storage = MemoryStorage::new();
+        let err = verify_fields(&storage, serde_json::json!({})).unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+
+        // The stored record is checked with its own scheme unless another one is given
+        let public_key = PublicKey::from_hex(PUBLIC_KEY).unwrap();
+        let (data_group, data_key, data_version) = ("group".to_string(), "key".to_string(), "1".to_string());
+        let hash = hash_data_v2(&public_key, &data_group, &data_key, b"block", &data_version);
+        let signature = SignatureHex::try_from(sign(&hash)).unwrap();
+        let record = storage.insert(&public_key, &data_group, &data_key, &"block".to_string(), DataEncoding::Utf8,
+                                    &None, &data_version, VersionPolicy::None, &signature, SigScheme::V2,
+                                    &generate_secret()).unwrap();
+        let response = verify_fields(&storage, serde_json::json!({})).unwrap();
+        assert_eq!(response, serde_json::json!({"valid": true, "sig_scheme": "v2", "key_type": "p256"}));
+        let response = verify_fields(&storage, serde_json::json!({"sig_scheme": "v1"})).unwrap();
+        assert_eq!(response["valid"], false);
+
+        // A tombstone has nothing to check
+        delete(&storage, &record);
+        let err = verify_fields(&storage, serde_json::json!({})).unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::utils::*;
+use crate::crypto::{generate_secret, hash_data_block};
+use crate::keys::PublicKey;
+use crate::types::SignatureHex;
+use crate::error::{Error, Result};
+use crate::page::{Page, Order};
+use crate::scheme::SigScheme;
+use crate::encoding::DataEncoding;
+use crate::version::VersionPolicy;
+use crate::block::{Block, BlockMeta, stored_data};
+use crate::nonce::{Nonce, NONCE_TTL};
+use crate::history::History;
+use crate::transparency::LogLeaf;
+use crate::merkle::Nodes;
+use crate::storage::Storage;
+
+
+type BlockIndex = (String, String, String);
+
+
+// The previous value of an entry changed inside a transaction
+enum Undo {
+    Block(BlockIndex, Option<Box<Block>>),
+    Nonce(String, Option<Nonce>),
+}
+
+
+#[derive(Default)]
+struct State {
+    last_id: i32,
+    // Ordered by (public_key, data_group, data_key) as the unique index of the database
+    blocks: BTreeMap<BlockIndex, Block>,
+    nonces: HashMap<String, Nonce>,
+    // Kept while a transaction runs, so only the touched entries are restored on failure
+    undo: Option<Vec<Undo>>,
+}
+
+
+impl State {
+    fn touch_block(&mut self, index: &BlockIndex) {
+        if let Some(undo) = &mut self.undo {
+            undo.push(Undo::Block(index.clone(), self.blocks.get(index).cloned().map(Box::new)));
+        }
+    }
+
+    fn touch_nonce(&mut self, value: &str) {
+        if let Some(undo) = &mut self.undo {
+            undo.push(Undo::Nonce(value.to_string(), self.nonces.get(value).cloned()));
+        }
+    }
+
+    fn rollback(&mut self, undo: Vec<Undo>) {
+        /* The entries are restored in reverse order, so each gets its value before the transaction */
+        for entry in undo.into_iter().rev() {
+            match entry {
+                Undo::Block(index, Some(record)) => { self.blocks.insert(index, *record); },
+                Undo::Block(index, None) => { self.blocks.remove(&index); },
+                Undo::Nonce(value, Some(nonce)) => { self.nonces.insert(value, nonce); },
+                Undo::Nonce(value, None) => { self.nonces.remove(&value); },
+            }
+        }
+    }
+}
+
+
+// Records and nonces in the memory of the process, for tests and ephemeral instances.
+// The history and the transparency log are not kept, their methods return Error::NotSupported.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: RwLock<State>,
+    writer: Mutex<()>,
+}
+
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap()
+    }
+
+    fn page<T, F>(&self, public_key: &PublicKey, data_group: &String,
+                  page: &Page, map: F) -> (Vec<T>, Option<String>) where F: Fn(&Block) -> T {
+        /* The same filters and order as Block::paginate of the database */
+        let public_key = public_key.to_hex();
+        let state = self.read();
+        let mut records: Vec<&Block> = state.blocks.values().filter(|r| {
+            r.public_key == public_key && &r.data_group == data_group
+                && (page.include_deleted || !r.deleted)
+                && page.prefix.as_ref().map_or(true, |prefix| r.data_key.starts_with(prefix))
+                && page.from.as_ref().map_or(true, |from| &r.data_key >= from)
+                && page.to.as_ref().map_or(true, |to| &r.data_key < to)
+        }).collect();
+        if page.order == Order::Id {
+            records.sort_by_key(|r| r.id);
+        }
+        if let Some(cursor) = &page.cursor {
+            records.retain(|r| match page.order {
+                Order::Key => &r.data_key > cursor,
+                Order::Id => r.id > page.cursor_id().unwrap_or(0),
+            });
+        }
+        let mut records: Vec<&Block> = records.into_iter().skip(page.offset as usize)
+                                              .take(page.limit as usize + 1).collect();
+        let next_cursor = Block::next_cursor(&mut records, page, |r| (r.id, &r.data_key));
+        (records.into_iter().map(map).collect(), next_cursor)
+    }
+}
+
+
+impl Storage for MemoryStorage {
+    fn check(&self, public_key: &PublicKey) -> Result<bool> {
+        let public_key = public_key.to_hex();
+        Ok(self.read().blocks.values().any(|r| r.public_key == public_key && !r.deleted))
+    }
+
+    fn groups(&self, public_key: &PublicKey) -> Result<Vec<String>> {
+        let public_key = public_key.to_hex();
+        let mut groups: Vec<String> = self.read().blocks.values()
+            .filter(|r| r.public_key == public_key && !r.deleted)
+            .map(|r| r.data_group.clone()).collect();
+        groups.dedup();
+        Ok(groups)
+    }
+
+    fn keys(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<String>, Option<String>)> {
+        Ok(self.page(public_key, data_group, page, |r| r.data_key.clone()))
+    }
+
+    fn list(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<Block>, Option<String>)> {
+        Ok(self.page(public_key, data_group, page, Block::clone))
+    }
+
+    fn meta(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<BlockMeta>, Option<String>)> {
+        Ok(self.page(public_key, data_group, page, |r| BlockMeta {
+            id: r.id,
+            data_key: r.data_key.clone(),
+            data_version: r.data_version.clone(),
+            signature: r.signature.clone(),
+            data_size: r.data_size,
+            data_hash: r.data_hash.clone(),
+            deleted: r.deleted,
+            sig_scheme: r.sig_scheme.clone(),
+            data_encoding: r.data_encoding.clone(),
+        }))
+    }
+
+    fn get(&self, public_key: &PublicKey, data_group: &String, data_key: &String) -> Result<Option<Block>> {
+        let index = (public_key.to_hex(), data_group.clone(), data_key.clone());
+        Ok(self.read().blocks.get(&index).cloned())
+    }
+
+    fn insert(&self, public_key: &PublicKey, data_group: &String, data_key: &String,
+              data_block: &String, data_encoding: DataEncoding, content_type: &Option<String>,
+              data_version: &String, version_policy: VersionPolicy, signature: &SignatureHex,
+              sig_scheme: SigScheme, secret: &Vec<u8>) -> Result<Block> {
+        // The records are kept as they are loaded from the database, with base64 text restored
+        let (data_block, data_bytes) = stored_data(data_block, data_encoding)?;
+        let raw_data = data_bytes.as_deref().unwrap_or(data_block.as_bytes());
+        let (data_size, data_hash) = (raw_data.len() as i32, hex_from_bytes(&hash_data_block(raw_data)));
+        let index = (public_key.to_hex(), data_group.clone(), data_key.clone());
+        let mut state = self.write();
+        if state.blocks.contains_key(&index) {
+            return Err(Error::Conflict);
+        }
+        state.touch_block(&index);
+        state.last_id += 1;
+        let record = Block {
+            id: state.last_id,
+            public_key: index.0.clone(),
+            data_group: data_group.clone(),
+            data_key: data_key.clone(),
+            data_block,
+            data_version: data_version.clone(),
+            signature: signature.to_hex(),
+            secret: hex_from_bytes(secret),
+            data_size,
+            data_hash,
+            deleted: false,
+            delete_signature: String::new(),
+            delete_challenge: String::new(),
+            sig_scheme: sig_scheme.name().to_string(),
+            key_type: public_key.key_type().name().to_string(),
+            data_encoding: data_encoding.name().to_string(),
+            data_bytes,
+            content_type: content_type.clone(),
+            previous_secret: String::new(),
+            version_policy: version_policy.name().to_string(),
+        }.with_data_block();
+        state.blocks.insert(index, record.clone());
+        Ok(record)
+    }
+
+    fn update(&self, record: &Block, data_block: &String, data_encoding: DataEncoding,
+              content_type: &Option<String>, data_version: &String, version_policy: VersionPolicy,
+              signature: &SignatureHex, sig_scheme: SigScheme, secret: &Vec<u8>) -> Result<Block> {
+        let (data_block, data_bytes) = stored_data(data_block, data_encoding)?;
+        let raw_data = data_bytes.as_deref().unwrap_or(data_block.as_bytes());
+        let (data_size, data_hash) = (raw_data.len() as i32, hex_from_bytes(&hash_data_block(raw_data)));
+        let index = (record.public_key.clone(), record.data_group.clone(), record.data_key.clone());
+        let mut state = self.write();
+        state.touch_block(&index);
+        match state.blocks.get_mut(&index) {
+            Some(stored) if stored.secret == record.secret => {
+                stored.data_block = data_bytes.as_deref().map(|raw| data_encoding.encode(raw)).unwrap_or(data_block);
+                stored.data_bytes = data_bytes;
+                stored.data_version = data_version.clone();
+                stored.signature = signature.to_hex();
+                stored.previous_secret = std::mem::replace(&mut stored.secret, hex_from_bytes(secret));
+                stored.data_size = data_size;
+                stored.data_hash = data_hash;
+                stored.data_encoding = data_encoding.name().to_string();
+                stored.content_type = content_type.clone();
+                stored.version_policy = version_policy.name().to_string();
+                stored.deleted = false;
+                stored.delete_signature = String::new();
+                stored.delete_challenge = String::new();
+                stored.sig_scheme = sig_scheme.name().to_string();
+                Ok(stored.clone())
+            },
+            _ => Err(Error::Conflict),
+        }
+    }
+
+    fn delete(&self, record: &Block, delete_signature: &SignatureHex, delete_challenge: &str) -> Result<()> {
+        let index = (record.public_key.clone(), record.data_group.clone(), record.data_key.clone());
+        let mut state = self.write();
+        state.touch_block(&index);
+        match state.blocks.get_mut(&index) {
+            Some(stored) if stored.secret == record.secret && !stored.deleted => {
+                stored.data_block = String::new();
+                stored.data_bytes = None;
+                stored.deleted = true;
+                stored.delete_signature = delete_signature.to_hex();
+                stored.delete_challenge = delete_challenge.to_string();
+                Ok(())
+            },
+            _ => Err(Error::Conflict),
+        }
+    }
+
+    fn issue_nonce(&self, purpose: &str) -> Result<Nonce> {
+        let now = timestamp();
+        let mut state = self.write();
+        state.nonces.retain(|_, nonce| nonce.expires_at > now);
+        state.last_id += 1;
+        let nonce = Nonce {
+            id: state.last_id,
+            value: hex_from_bytes(&generate_secret()),
+            created_at: now,
+            expires_at: now + NONCE_TTL,
+            purpose: purpose.to_string(),
+        };
+        state.touch_nonce(&nonce.value);
+        state.nonces.insert(nonce.value.clone(), nonce.clone());
+        Ok(nonce)
+    }
+
+    fn consume_nonce(&self, value: &str, purpose: &str) -> Result<bool> {
+        let mut state = self.write();
+        match state.nonces.get(value) {
+            Some(nonce) if nonce.purpose == purpose && nonce.expires_at > timestamp() => {
+                state.touch_nonce(value);
+                state.nonces.remove(value);
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    fn run_transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        /* Writers are serialized, the entries touched by f are restored if it fails.
+           Readers are not isolated from the writes made so far. */
+        let _writer = self.writer.lock().unwrap();
+        self.write().undo = Some(Vec::new());
+        let result = f();
+        let mut state = self.write();
+        let undo = state.undo.take().unwrap_or_default();
+        if result.is_err() {
+            // The ids taken by the failed writes are not reused, as in the database
+            state.rollback(undo);
+        }
+        result
+    }
+
+    fn history(&self, _: &PublicKey, _: &String, _: &String,
+               _: i64, _: Option<i32>) -> Result<(Vec<History>, Option<i32>)> {
+        Err(history_not_supported())
+    }
+
+    fn log_size(&self) -> Result<i64> {
+        Err(log_not_supported())
+    }
+
+    fn log_leaves(&self, _: i64, _: i64) -> Result<Vec<LogLeaf>> {
+        Err(log_not_supported())
+    }
+
+    fn log_record(&self, _: &PublicKey, _: &String, _: &String) -> Result<Vec<LogLeaf>> {
+        Err(log_not_supported())
+    }
+
+    fn log_tree(&self) -> Result<Box<dyn Nodes + '_>> {
+        Err(log_not_supported())
+    }
+}
+
+
+fn history_not_supported() -> Error {
+    Error::NotSupported("the history is not kept by the memory storage".to_string())
+}
+
+
+fn log_not_supported() -> Error {
+    Error::NotSupported("the transparency log is not kept by the memory storage".to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use crate::page::PageQuery;
+
+    fn public_key() -> PublicKey {
+        PublicKey::from_hex("ed25519:D75A980182B10AB7D54BFED3C964073A0EE172F3DAA62325AF021A68F707511A").unwrap()
+    }
+
+    fn signature() -> SignatureHex {
+        SignatureHex::try_from("0".repeat(128)).unwrap()
+    }
+
+    fn page(limit: i64, order: &str, cursor: Option<&str>) -> Page {
+        PageQuery {
+            limit: Some(limit.to_string()), cursor: cursor.map(str::to_string),
+            order: Some(order.to_string()), ..PageQuery::default()
+        }.into_page(limit).unwrap()
+    }
+
+    fn insert_block(storage: &dyn Storage, data_key: &str, data_block: &str,
+                    data_encoding: DataEncoding) -> Result<Block> {
+        storage.insert(&public_key(), &"group".to_string(), &data_key.to_string(), &data_block.to_string(),
+                       data_encoding, &None, &"1".to_string(), VersionPolicy::None, &signature(),
+                       SigScheme::V1, &generate_secret())
+    }
+
+    fn update_block(storage: &dyn Storage, record: &Block, data_block: &str, data_encoding: DataEncoding,
+                    data_version: &str, sig_scheme: SigScheme) -> Result<Block> {
+        storage.update(record, &data_block.to_string(), data_encoding, &None, &data_version.to_string(),
+                       VersionPolicy::None, &signature(), sig_scheme, &generate_secret())
+    }
+
+    fn insert(storage: &MemoryStorage, data_key: &str) -> Block {
+        insert_block(storage, data_key, "block", DataEncoding::Utf8).unwrap()
+    }
+
+    #[test]
+    fn test_insert_get() {
+        let storage = MemoryStorage::new();
+        assert_eq!(storage.check(&public_key()).unwrap(), false);
+        let record = insert(&storage, "key");
+        assert_eq!(record.data_size, 5);
+        assert_eq!(storage.check(&public_key()).unwrap(), true);
+        assert_eq!(storage.groups(&public_key()).unwrap(), vec!["group".to_string()]);
+
+        let stored = storage.get(&public_key(), &"group".to_string(), &"key".to_string()).unwrap().unwrap();
+        assert_eq!(stored.id, record.id);
+        assert_eq!(stored.public_key, public_key().to_hex());
+        assert_eq!(stored.key_type, "ed25519");
+
+        let result = insert_block(&storage, "key", "block", DataEncoding::Utf8);
+        assert!(matches!(result, Err(Error::Conflict)));
+    }
+
+    #[test]
+    fn test_update_delete() {
+        let storage = MemoryStorage::new();
+        let record = insert(&storage, "key");
+        let updated = update_block(&storage, &record, "new block", DataEncoding::Utf8, "2", SigScheme::V2).unwrap();
+        assert_eq!(updated.data_version, "2");
+        assert_eq!(updated.sig_scheme, "v2");
+        assert_ne!(updated.secret, record.secret);
+
+        // The secret of the old state cannot be used again
+        let result = update_block(&storage, &record, "other block", DataEncoding::Utf8, "3", SigScheme::V1);
+        assert!(matches!(result, Err(Error::Conflict)));
+        assert!(matches!(storage.delete(&record, &signature(), ""), Err(Error::Conflict)));
+
+        storage.delete(&updated, &signature(), "").unwrap();
+        let tombstone = storage.get(&public_key(), &"group".to_string(), &"key".to_string()).unwrap().unwrap();
+        assert_eq!(tombstone.deleted, true);
+        assert_eq!(tombstone.data_block, "");
+        assert_eq!(storage.check(&public_key()).unwrap(), false);
+    }
+
+    #[test]
+    fn test_base64() {
+        let storage = MemoryStorage::new();
+        let record = insert_block(&storage, "key", "AP8B", DataEncoding::Base64).unwrap();
+        assert_eq!(record.data_block, "AP8B");
+        assert_eq!(record.data_encoding, "base64");
+        assert_eq!(record.raw_data(), &[0, 255, 1]);
+        assert_eq!(record.data_size, 3);
+
+        let result = update_block(&storage, &record, "A", DataEncoding::Base64, "2", SigScheme::V1);
+        assert!(matches!(result, Err(Error::BadEncoding(_))));
+    }
+
+    #[test]
+    fn test_pagination() {
+        let storage = MemoryStorage::new();
+        for data_key in ["c", "a", "b"].iter() {
+            insert(&storage, data_key);
+        }
+
+        let (keys, next_cursor) = storage.keys(&public_key(), &"group".to_string(), &page(2, "key", None)).unwrap();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(next_cursor, Some("b".to_string()));
+        let (keys, next_cursor) = storage.keys(&public_key(), &"group".to_string(), &page(2, "key", Some("b"))).unwrap();
+        assert_eq!(keys, vec!["c"]);
+        assert_eq!(next_cursor, None);
+
+        let (records, next_cursor) = storage.list(&public_key(), &"group".to_string(), &page(2, "id", None)).unwrap();
+        assert_eq!(records.iter().map(|r| r.data_key.as_str()).collect::<Vec<_>>(), vec!["c", "a"]);
+        assert_eq!(next_cursor, Some(records[1].id.to_string()));
+    }
+
+    #[test]
+    fn test_nonce() {
+        let storage = MemoryStorage::new();
+        let nonce = storage.issue_nonce("insert").unwrap();
+        assert_eq!(storage.consume_nonce(&nonce.value, "auth").unwrap(), false);
+        assert_eq!(storage.consume_nonce(&nonce.value, "insert").unwrap(), true);
+        assert_eq!(storage.consume_nonce(&nonce.value, "insert").unwrap(), false);
+    }
+
+    #[test]
+    fn test_not_supported() {
+        let storage = MemoryStorage::new();
+        let result = storage.history(&public_key(), &"group".to_string(), &"key".to_string(), 10, None);
+        assert!(matches!(result, Err(Error::NotSupported(_))));
+        let err = storage.log_size().unwrap_err();
+        assert_eq!(err.code(), "not_supported");
+        assert!(storage.log_tree().is_err());
+    }
+
+    #[test]
+    fn test_write_transaction() {
+        let storage = MemoryStorage::new();
+        let storage: &dyn Storage = &storage;
+        let result: Result<()> = storage.write_transaction(|| {
+            insert_block(storage, "key", "block", DataEncoding::Utf8)?;
+            Err(Error::Conflict)
+        });
+        assert!(result.is_err());
+        assert_eq!(storage.check(&public_key()).unwrap(), false);
+
+        // Updated records and consumed nonces are restored as well
+        let record = insert_block(storage, "key", "block", DataEncoding::Utf8).unwrap();
+        let nonce = storage.issue_nonce("insert").unwrap();
+        let result: Result<()> = storage.write_transaction(|| {
+            update_block(storage, &record, "new block", DataEncoding::Utf8, "2", SigScheme::V1)?;
+            storage.consume_nonce(&nonce.value, "insert")?;
+            insert_block(storage, "other", "block", DataEncoding::Utf8)?;
+            Err(Error::Conflict)
+        });
+        assert!(result.is_err());
+        let stored = storage.get(&public_key(), &"group".to_string(), &"key".to_string()).unwrap().unwrap();
+        assert_eq!(stored.data_block, "block");
+        assert_eq!(stored.secret, record.secret);
+        assert!(storage.get(&public_key(), &"group".to_string(), &"other".to_string()).unwrap().is_none());
+        assert_eq!(storage.consume_nonce(&nonce.value, "insert").unwrap(), true);
+    }
+}
@@ -0,0 +1,295 @@
+/* Merkle tree hashing and proofs according to RFC 6962 (Certificate Transparency) */
+
+use sha2::{Sha256, Digest};
+
+use crate::error::Result;
+
+
+pub fn leaf_hash(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(&[0u8]);
+    hasher.input(data);
+    hasher.result().to_vec()
+}
+
+
+pub fn node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(&[1u8]);
+    hasher.input(left);
+    hasher.input(right);
+    hasher.result().to_vec()
+}
+
+
+// Source of the hashes of complete subtrees, so a root or a proof needs
+// a logarithmic number of them instead of all the leaves
+pub trait Nodes {
+    /* Hash of the subtree of 2^level leaves starting with the leaf index << level,
+       level 0 is the leaf hash */
+    fn node(&self, level: u32, index: u64) -> Result<Vec<u8>>;
+}
+
+
+fn split(n: u64) -> u64 {
+    /* The largest power of two smaller than n */
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+
+pub fn root(nodes: &dyn Nodes, start: u64, size: u64) -> Result<Vec<u8>> {
+    /* Every subtree of the split starts at a multiple of its largest power of two,
+       so it consists of complete subtrees only */
+    if size == 0 {
+        return Ok(Sha256::digest(&[]).to_vec());
+    }
+    if size.is_power_of_two() {
+        let level = size.trailing_zeros();
+        return nodes.node(level, start >> level);
+    }
+    let k = split(size);
+    Ok(node_hash(&root(nodes, start, k)?, &root(nodes, start + k, size - k)?))
+}
+
+
+pub fn append(nodes: &dyn Nodes, index: u64, leaf_hash: &[u8]) -> Result<Vec<(u32, u64, Vec<u8>)>> {
+    /* The nodes completed by the leaf (level, index, hash), the leaf must be the last one */
+    let mut completed = vec![];
+    let (mut level, mut index, mut hash) = (0, index, leaf_hash.to_vec());
+    while index & 1 == 1 {
+        hash = node_hash(&nodes.node(level, index - 1)?, &hash);
+        level += 1;
+        index >>= 1;
+        completed.push((level, index, hash.clone()));
+    }
+    Ok(completed)
+}
+
+
+pub fn inclusion_proof(nodes: &dyn Nodes, index: u64, size: u64) -> Result<Vec<Vec<u8>>> {
+    path(nodes, index, 0, size)
+}
+
+
+fn path(nodes: &dyn Nodes, index: u64, start: u64, size: u64) -> Result<Vec<Vec<u8>>> {
+    if size <= 1 {
+        return Ok(vec![]);
+    }
+    let k = split(size);
+    if index < k {
+        let mut proof = path(nodes, index, start, k)?;
+        proof.push(root(nodes, start + k, size - k)?);
+        Ok(proof)
+    } else {
+        let mut proof = path(nodes, index - k, start + k, size - k)?;
+        proof.push(root(nodes, start, k)?);
+        Ok(proof)
+    }
+}
+
+
+pub fn consistency_proof(nodes: &dyn Nodes, first: u64, second: u64) -> Result<Vec<Vec<u8>>> {
+    if first == 0 || first > second {
+        return Ok(vec![]);
+    }
+    subproof(nodes, first, 0, second, true)
+}
+
+
+fn subproof(nodes: &dyn Nodes, m: u64, start: u64, size: u64, complete: bool) -> Result<Vec<Vec<u8>>> {
+    if m == size {
+        return Ok(if complete { vec![] } else { vec![root(nodes, start, size)?] });
+    }
+    let k = split(size);
+    if m <= k {
+        let mut proof = subproof(nodes, m, start, k, complete)?;
+        proof.push(root(nodes, start + k, size - k)?);
+        Ok(proof)
+    } else {
+        let mut proof = subproof(nodes, m - k, start + k, size - k, false)?;
+        proof.push(root(nodes, start, k)?);
+        Ok(proof)
+    }
+}
+
+
+#[cfg(test)]
+pub fn verify_inclusion(index: usize, size: usize, leaf: &[u8],
+                        proof: &[Vec<u8>], root: &[u8]) -> bool {
+    if index >= size {
+        return false;
+    }
+    let (mut fn_, mut sn) = (index, size - 1);
+    let mut r = leaf.to_vec();
+    for p in proof {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            r = node_hash(p, &r);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && r == root
+}
+
+
+#[cfg(test)]
+pub fn verify_consistency(first: usize, second: usize, first_root: &[u8],
+                          second_root: &[u8], proof: &[Vec<u8>]) -> bool {
+    if first == 0 || first > second {
+        return false;
+    }
+    if first == second {
+        return proof.is_empty() && first_root == second_root;
+    }
+    let mut path = proof.to_vec();
+    if first & (first - 1) == 0 {
+        path.insert(0, first_root.to_vec());
+    }
+    if path.is_empty() {
+        return false;
+    }
+    let (mut fn_, mut sn) = (first - 1, second - 1);
+    while fn_ & 1 == 1 {
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    let mut fr = path[0].clone();
+    let mut sr = path[0].clone();
+    for c in path[1..].iter() {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    fr == first_root && sr == second_root && sn == 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use test::Bencher;
+    use crate::error::Error;
+
+    // The nodes kept the way the log keeps them, filled by append
+    struct Tree {
+        leaves: Vec<Vec<u8>>,
+        nodes: HashMap<(u32, u64), Vec<u8>>,
+    }
+
+    impl Nodes for Tree {
+        fn node(&self, level: u32, index: u64) -> Result<Vec<u8>> {
+            match level {
+                0 => self.leaves.get(index as usize).cloned(),
+                level => self.nodes.get(&(level, index)).cloned(),
+            }.ok_or(Error::NotFound)
+        }
+    }
+
+    fn build_tree(n: usize) -> Tree {
+        let mut tree = Tree { leaves: vec![], nodes: HashMap::new() };
+        for i in 0..n {
+            let leaf = leaf_hash(&i.to_be_bytes());
+            tree.leaves.push(leaf.clone());
+            for (level, index, hash) in append(&tree, i as u64, &leaf).unwrap() {
+                tree.nodes.insert((level, index), hash);
+            }
+        }
+        tree
+    }
+
+    fn leaves_root(leaves: &[Vec<u8>]) -> Vec<u8> {
+        /* The definition of RFC 6962 over all the leaves */
+        match leaves.len() {
+            0 => Sha256::digest(&[]).to_vec(),
+            1 => leaves[0].clone(),
+            n => {
+                let k = split(n as u64) as usize;
+                node_hash(&leaves_root(&leaves[..k]), &leaves_root(&leaves[k..]))
+            }
+        }
+    }
+
+    #[test]
+    fn test_root() {
+        let tree = build_tree(3);
+        assert_eq!(root(&tree, 0, 1).unwrap(), tree.leaves[0]);
+        assert_eq!(
+            root(&tree, 0, 3).unwrap(),
+            node_hash(&node_hash(&tree.leaves[0], &tree.leaves[1]), &tree.leaves[2])
+        );
+        let tree = build_tree(21);
+        for n in 0..=21 {
+            assert_eq!(root(&tree, 0, n as u64).unwrap(), leaves_root(&tree.leaves[..n]));
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let tree = build_tree(8);
+        assert_eq!(tree.nodes.len(), 7);
+        assert_eq!(tree.nodes[&(3, 0)], leaves_root(&tree.leaves));
+        assert!(append(&tree, 9, &leaf_hash(b"other")).is_err());
+    }
+
+    #[test]
+    fn test_inclusion_proof() {
+        for n in 1..12 {
+            let tree = build_tree(n);
+            let r = root(&tree, 0, n as u64).unwrap();
+            for i in 0..n {
+                let proof = inclusion_proof(&tree, i as u64, n as u64).unwrap();
+                assert!(verify_inclusion(i, n, &tree.leaves[i], &proof, &r));
+                assert!(!verify_inclusion(i, n, &leaf_hash(b"other"), &proof, &r));
+            }
+        }
+    }
+
+    #[test]
+    fn test_consistency_proof() {
+        for n in 1..12 {
+            let tree = build_tree(n);
+            let r = root(&tree, 0, n as u64).unwrap();
+            for m in 1..=n {
+                let proof = consistency_proof(&tree, m as u64, n as u64).unwrap();
+                let first_root = root(&tree, 0, m as u64).unwrap();
+                assert!(verify_consistency(m, n, &first_root, &r, &proof));
+                if m < n {
+                    let forged = leaves_root(&build_tree(m + 1).leaves[1..]);
+                    assert!(!verify_consistency(m, n, &forged, &r, &proof));
+                }
+            }
+        }
+    }
+
+    #[bench]
+    fn bench_root(b: &mut Bencher) {
+        let tree = build_tree(10000);
+        b.iter(|| root(&tree, 0, 10000).unwrap());
+    }
+}
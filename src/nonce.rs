@@ -0,0 +1,56 @@
+use serde_derive::{Serialize, Deserialize};
+use diesel;
+use diesel::prelude::*;
+
+use crate::utils::*;
+use crate::db::DbConnection;
+use crate::crypto::generate_secret;
+use crate::error::Result;
+use crate::schema::issued_nonce;
+
+// Lifetime of an issued nonce in seconds
+pub const NONCE_TTL: i64 = 300;
+
+// Nonces for first-time inserts and challenges for authorization are not interchangeable
+pub const PURPOSE_INSERT: &str = "insert";
+pub const PURPOSE_AUTH: &str = "auth";
+
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
+pub struct Nonce {
+    #[serde(skip)]
+    pub id: i32,
+    #[serde(rename = "nonce")]
+    pub value: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    #[serde(skip)]
+    pub purpose: String,
+}
+
+
+impl Nonce {
+    pub fn issue(conn: &DbConnection, purpose: &str) -> Result<Self> {
+        /* Creates a random nonce, the expired ones are cleaned up on the way */
+        let now = timestamp();
+        diesel::delete(issued_nonce::table.filter(issued_nonce::expires_at.le(now))).execute(conn)?;
+        let value = hex_from_bytes(&generate_secret());
+        diesel::insert_into(issued_nonce::table).values((
+            issued_nonce::value.eq(&value),
+            issued_nonce::created_at.eq(now),
+            issued_nonce::expires_at.eq(now + NONCE_TTL),
+            issued_nonce::purpose.eq(purpose),
+        )).execute(conn)?;
+        Ok(issued_nonce::table.filter(issued_nonce::value.eq(&value)).first(conn)?)
+    }
+
+    pub fn consume(conn: &DbConnection, value: &str, purpose: &str) -> Result<bool> {
+        /* A nonce can be used only once, before it expires and for its purpose */
+        let count = diesel::delete(
+            issued_nonce::table.filter(issued_nonce::value.eq(value))
+                               .filter(issued_nonce::purpose.eq(purpose))
+                               .filter(issued_nonce::expires_at.gt(timestamp()))
+        ).execute(conn)?;
+        Ok(count == 1)
+    }
+}
@@ -0,0 +1,166 @@
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+pub const MAX_KEYS_PAGE_SIZE: i64 = 1000;
+pub const MAX_LIST_PAGE_SIZE: i64 = 100;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Order {
+    Key,
+    Id,
+}
+
+
+pub struct Page {
+    pub limit: i64,
+    pub offset: i64,
+    pub cursor: Option<String>,
+    pub order: Order,
+    pub prefix: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub include_deleted: bool,
+}
+
+
+// The numbers and flags are taken as text, so a malformed value is reported
+// as bad_query instead of being ignored
+#[derive(FromForm, Default)]
+pub struct PageQuery {
+    pub limit: Option<String>,
+    pub offset: Option<String>,
+    pub cursor: Option<String>,
+    pub order: Option<String>,
+    pub prefix: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub include_deleted: Option<String>,
+}
+
+
+pub fn parse_query<T: FromStr>(name: &str, value: Option<&str>) -> Result<Option<T>> {
+    /* A query parameter that is absent gives None, a malformed one gives Error::BadQuery */
+    match value {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(
+            |_| Error::BadQuery(format!("{} has invalid value {:?}", name, value))
+        ),
+    }
+}
+
+
+impl PageQuery {
+    pub fn into_page(self, max_limit: i64) -> Result<Page> {
+        /* Limit is clamped by max_limit that also is the default */
+        let limit = match parse_query::<i64>("limit", self.limit.as_deref())? {
+            Some(limit) if limit < 1 => {
+                return Err(Error::BadQuery("limit must be positive".to_string()));
+            },
+            Some(limit) => limit.min(max_limit),
+            None => max_limit,
+        };
+        let offset = match parse_query::<i64>("offset", self.offset.as_deref())? {
+            Some(offset) if offset < 0 => {
+                return Err(Error::BadQuery("offset must not be negative".to_string()));
+            },
+            Some(offset) => offset,
+            None => 0,
+        };
+        let order = match self.order.as_deref() {
+            None | Some("key") => Order::Key,
+            Some("id") => Order::Id,
+            Some(other) => {
+                return Err(Error::BadQuery(format!("unknown order {:?}", other)));
+            }
+        };
+        if let (Order::Id, Some(cursor)) = (order, &self.cursor) {
+            if cursor.parse::<i32>().is_err() {
+                return Err(Error::BadQuery("cursor must be an id".to_string()));
+            }
+        }
+        Ok(Page {
+            limit, offset, order,
+            cursor: self.cursor,
+            prefix: self.prefix.filter(|prefix| !prefix.is_empty()),
+            from: self.from,
+            to: self.to,
+            include_deleted: parse_query("include_deleted", self.include_deleted.as_deref())?.unwrap_or(false),
+        })
+    }
+}
+
+
+impl Page {
+    pub fn cursor_id(&self) -> Option<i32> {
+        self.cursor.as_ref().and_then(|cursor| cursor.parse().ok())
+    }
+
+    pub fn cursor_of(&self, id: i32, data_key: &str) -> String {
+        match self.order {
+            Order::Key => data_key.to_string(),
+            Order::Id => id.to_string(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(limit: Option<&str>, offset: Option<&str>, order: Option<&str>, cursor: Option<&str>) -> PageQuery {
+        PageQuery {
+            limit: limit.map(str::to_string),
+            offset: offset.map(str::to_string),
+            order: order.map(str::to_string),
+            cursor: cursor.map(str::to_string),
+            ..PageQuery::default()
+        }
+    }
+
+    #[test]
+    fn test_limit() {
+        assert_eq!(query(None, None, None, None).into_page(100).unwrap().limit, 100);
+        assert_eq!(query(Some("10"), None, None, None).into_page(100).unwrap().limit, 10);
+        assert_eq!(query(Some("1000"), None, None, None).into_page(100).unwrap().limit, 100);
+        assert!(matches!(query(Some("0"), None, None, None).into_page(100), Err(Error::BadQuery(_))));
+        assert!(matches!(query(Some("abc"), None, None, None).into_page(100), Err(Error::BadQuery(_))));
+    }
+
+    #[test]
+    fn test_offset() {
+        assert_eq!(query(None, None, None, None).into_page(100).unwrap().offset, 0);
+        assert_eq!(query(None, Some("20"), None, None).into_page(100).unwrap().offset, 20);
+        assert!(matches!(query(None, Some("-1"), None, None).into_page(100), Err(Error::BadQuery(_))));
+        assert!(matches!(query(None, Some("1.5"), None, None).into_page(100), Err(Error::BadQuery(_))));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let page = query(None, None, None, Some("key 5")).into_page(100).unwrap();
+        assert!(page.order == Order::Key);
+        assert_eq!(page.cursor_id(), None);
+        assert_eq!(page.cursor_of(7, "key 7"), "key 7");
+
+        let page = query(None, None, Some("id"), Some("5")).into_page(100).unwrap();
+        assert!(page.order == Order::Id);
+        assert_eq!(page.cursor_id(), Some(5));
+        assert_eq!(page.cursor_of(7, "key 7"), "7");
+
+        assert!(matches!(query(None, None, Some("id"), Some("key 5")).into_page(100), Err(Error::BadQuery(_))));
+        assert!(matches!(query(None, None, Some("size"), None).into_page(100), Err(Error::BadQuery(_))));
+    }
+
+    #[test]
+    fn test_filters() {
+        let page = PageQuery {
+            prefix: Some("".to_string()), include_deleted: Some("true".to_string()), ..PageQuery::default()
+        }.into_page(100).unwrap();
+        assert_eq!(page.prefix, None);
+        assert_eq!(page.include_deleted, true);
+        let result = PageQuery { include_deleted: Some("yes".to_string()), ..PageQuery::default() }.into_page(100);
+        assert!(matches!(result, Err(Error::BadQuery(_))));
+    }
+}
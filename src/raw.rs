@@ -0,0 +1,184 @@
+use std::io::Cursor;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::{Request, Outcome};
+
+use crate::error::{Error, Result};
+use crate::block::Block;
+
+pub const MAX_CONTENT_TYPE_LENGTH: usize = 255;
+
+
+pub fn check_content_type(content_type: &str) -> Result<String> {
+    /* The media type goes into the header of /raw as it is given by the owner,
+       so only a valid one is accepted, it is stored in the normalized form */
+    if content_type.len() > MAX_CONTENT_TYPE_LENGTH {
+        return Err(Error::BadContentType(format!("must be up to {} characters", MAX_CONTENT_TYPE_LENGTH)));
+    }
+    if !content_type.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        return Err(Error::BadContentType("must contain printable ASCII characters only".to_string()));
+    }
+    let parsed: ContentType = content_type.parse().map_err(Error::BadContentType)?;
+    Ok(parsed.to_string())
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    Full,
+    // First and last bytes, inclusive
+    Partial(usize, usize),
+    Unsatisfiable,
+}
+
+
+pub fn parse_range(header: &str, size: usize) -> ByteRange {
+    /* A single range of bytes: first-last, first- or -suffix_length.
+       Malformed headers and several ranges are ignored, so the whole block is sent */
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.find('-') {
+        Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+        None => return ByteRange::Full,
+    };
+    if first.is_empty() {
+        return match last.parse::<usize>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(length) => ByteRange::Partial(size.saturating_sub(length), size - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let first = match first.parse::<usize>() {
+        Ok(first) => first,
+        Err(_) => return ByteRange::Full,
+    };
+    let last = match last {
+        "" => None,
+        last => match last.parse::<usize>() {
+            Ok(last) if last >= first => Some(last),
+            _ => return ByteRange::Full,
+        },
+    };
+    if first >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(first, last.unwrap_or(size - 1).min(size - 1))
+}
+
+
+// Range and If-Range headers of the request
+pub struct RangeRequest {
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+}
+
+
+impl<'a, 'r> FromRequest<'a, 'r> for RangeRequest {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<RangeRequest, ()> {
+        let headers = request.headers();
+        Outcome::Success(RangeRequest {
+            range: headers.get_one("Range").map(str::to_string),
+            if_range: headers.get_one("If-Range").map(str::to_string),
+        })
+    }
+}
+
+
+// The raw bytes of a record served to browsers, data_hash is the entity tag
+pub struct RawBlock {
+    data: Vec<u8>,
+    content_type: ContentType,
+    etag: String,
+    range: ByteRange,
+}
+
+
+impl RawBlock {
+    pub fn new(record: Block, request: &RangeRequest) -> Self {
+        /* Without content_type binary blocks are sent as application/octet-stream
+           and text blocks as text/plain */
+        let content_type = match &record.content_type {
+            Some(content_type) => content_type.parse().unwrap_or(ContentType::Binary),
+            None if record.data_bytes.is_some() => ContentType::Binary,
+            None => ContentType::Plain,
+        };
+        let etag = format!("\"{}\"", record.data_hash);
+        let data = match record.data_bytes {
+            Some(data_bytes) => data_bytes,
+            None => record.data_block.into_bytes(),
+        };
+        // A range of another version of the block is not sent, If-Range is compared with ETag
+        let range = match (&request.range, &request.if_range) {
+            (Some(_), Some(if_range)) if *if_range != etag => ByteRange::Full,
+            (Some(range), _) => parse_range(range, data.len()),
+            (None, _) => ByteRange::Full,
+        };
+        RawBlock { data, content_type, etag, range }
+    }
+}
+
+
+impl<'r> Responder<'r> for RawBlock {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let size = self.data.len();
+        let mut response = Response::build();
+        response.header(self.content_type)
+                .raw_header("Accept-Ranges", "bytes")
+                .raw_header("ETag", self.etag)
+                // The content is uploaded by the owner, it must not run scripts on this origin
+                .raw_header("X-Content-Type-Options", "nosniff")
+                .raw_header("Content-Security-Policy", "sandbox");
+        match self.range {
+            ByteRange::Full => {
+                response.sized_body(Cursor::new(self.data));
+            },
+            ByteRange::Partial(first, last) => {
+                response.status(Status::PartialContent)
+                        .raw_header("Content-Range", format!("bytes {}-{}/{}", first, last, size))
+                        .sized_body(Cursor::new(self.data[first..=last].to_vec()));
+            },
+            ByteRange::Unsatisfiable => {
+                response.status(Status::RangeNotSatisfiable)
+                        .raw_header("Content-Range", format!("bytes */{}", size));
+            },
+        }
+        response.ok()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-3", 10), ByteRange::Partial(0, 3));
+        assert_eq!(parse_range("bytes=4-", 10), ByteRange::Partial(4, 9));
+        assert_eq!(parse_range("bytes=4-100", 10), ByteRange::Partial(4, 9));
+        assert_eq!(parse_range("bytes=-3", 10), ByteRange::Partial(7, 9));
+        assert_eq!(parse_range("bytes=-30", 10), ByteRange::Partial(0, 9));
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-3", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Full);
+        assert_eq!(parse_range("items=0-3", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=a-3", 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        assert_eq!(check_content_type("image/png").unwrap(), "image/png");
+        assert_eq!(check_content_type("text/plain; charset=utf-8").unwrap(), "text/plain; charset=utf-8");
+        assert!(matches!(check_content_type("png"), Err(Error::BadContentType(_))));
+        assert!(matches!(check_content_type("text/html\r\nSet-Cookie: a=b"), Err(Error::BadContentType(_))));
+        assert!(check_content_type(&format!("image/{}", "x".repeat(MAX_CONTENT_TYPE_LENGTH))).is_err());
+    }
+}
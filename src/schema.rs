@@ -8,5 +8,82 @@ table! {
         data_version -> Text,
         signature -> Text,
         secret -> Text,
+        data_size -> Integer,
+        data_hash -> Text,
+        deleted -> Bool,
+        delete_signature -> Text,
+        delete_challenge -> Text,
+        sig_scheme -> Text,
+        key_type -> Text,
+        data_encoding -> Text,
+        data_bytes -> Nullable<Binary>,
+        content_type -> Nullable<Text>,
+        previous_secret -> Text,
+        version_policy -> Text,
+    }
+}
+
+table! {
+    block_history (id) {
+        id -> Integer,
+        block_id -> Integer,
+        public_key -> Text,
+        data_group -> Text,
+        data_key -> Text,
+        data_block -> Text,
+        data_version -> Text,
+        signature -> Text,
+        data_size -> Integer,
+        data_hash -> Text,
+        action -> Text,
+        archived_at -> BigInt,
+        sig_scheme -> Text,
+        data_encoding -> Text,
+        data_bytes -> Nullable<Binary>,
+        delete_signature -> Text,
+        delete_challenge -> Text,
+    }
+}
+
+table! {
+    log_leaf (id) {
+        id -> Integer,
+        leaf_index -> BigInt,
+        leaf_hash -> Text,
+        action -> Text,
+        public_key -> Text,
+        data_group -> Text,
+        data_key -> Text,
+        data_hash -> Text,
+        data_version -> Text,
+        signature -> Text,
+        created_at -> BigInt,
+        delete_signature -> Text,
+        delete_challenge -> Text,
+    }
+}
+
+table! {
+    log_node (id) {
+        id -> Integer,
+        level -> Integer,
+        node_index -> BigInt,
+        node_hash -> Text,
+    }
+}
+
+table! {
+    issued_nonce (id) {
+        id -> Integer,
+        value -> Text,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+        purpose -> Text,
+    }
+}
+
+table! {
+    backfill (name) {
+        name -> Text,
     }
 }
@@ -0,0 +1,58 @@
+use std::str::FromStr;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::keys::PublicKey;
+use crate::types::SignatureHex;
+use crate::crypto::{check_data_signature, check_data_signature_v2};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SigScheme {
+    V1,
+    V2,
+}
+
+
+impl Default for SigScheme {
+    fn default() -> Self {
+        // Clients that do not send sig_scheme sign by the first scheme
+        SigScheme::V1
+    }
+}
+
+
+impl FromStr for SigScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(SigScheme::V1),
+            "v2" => Ok(SigScheme::V2),
+            _ => Err(format!("unknown signature scheme {:?}", s)),
+        }
+    }
+}
+
+
+impl SigScheme {
+    pub fn check_data_signature(&self, public_key: &PublicKey, data_group: &String, data_key: &String,
+                                data_block: &[u8], data_version: &String,
+                                signature: &SignatureHex) -> bool {
+        match self {
+            SigScheme::V1 => check_data_signature(
+                public_key, data_group, data_key, data_block, data_version, signature
+            ),
+            SigScheme::V2 => check_data_signature_v2(
+                public_key, data_group, data_key, data_block, data_version, signature
+            ),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SigScheme::V1 => "v1",
+            SigScheme::V2 => "v2",
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use std::ops::Deref;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{Request, Rocket, State, Outcome};
+use rocket::config::ConfigError;
+use rocket::fairing::AdHoc;
+use diesel::Connection as _;
+
+use crate::db::{self, DbConfig, DbConnection};
+use crate::keys::PublicKey;
+use crate::types::SignatureHex;
+use crate::error::Result;
+use crate::page::Page;
+use crate::scheme::SigScheme;
+use crate::encoding::DataEncoding;
+use crate::version::VersionPolicy;
+use crate::block::{Block, BlockMeta};
+use crate::history::History;
+use crate::nonce::Nonce;
+use crate::memory::MemoryStorage;
+use crate::transparency::{LogLeaf, StoredTree};
+use crate::merkle::Nodes;
+
+
+// Persistence of the records and nonces the API methods depend on
+pub trait Storage {
+    fn check(&self, public_key: &PublicKey) -> Result<bool>;
+
+    fn groups(&self, public_key: &PublicKey) -> Result<Vec<String>>;
+
+    fn keys(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<String>, Option<String>)>;
+
+    fn list(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<Block>, Option<String>)>;
+
+    fn meta(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<BlockMeta>, Option<String>)>;
+
+    fn get(&self, public_key: &PublicKey, data_group: &String, data_key: &String) -> Result<Option<Block>>;
+
+    /* Writes return Error::Conflict if the record has been changed in between:
+       insert if it exists, update and delete if its secret is not the one of the given record */
+
+    fn insert(&self, public_key: &PublicKey, data_group: &String, data_key: &String,
+              data_block: &String, data_encoding: DataEncoding, content_type: &Option<String>,
+              data_version: &String, version_policy: VersionPolicy, signature: &SignatureHex,
+              sig_scheme: SigScheme, secret: &Vec<u8>) -> Result<Block>;
+
+    fn update(&self, record: &Block, data_block: &String, data_encoding: DataEncoding,
+              content_type: &Option<String>, data_version: &String, version_policy: VersionPolicy,
+              signature: &SignatureHex, sig_scheme: SigScheme, secret: &Vec<u8>) -> Result<Block>;
+
+    fn delete(&self, record: &Block, delete_signature: &SignatureHex, delete_challenge: &str) -> Result<()>;
+
+    fn issue_nonce(&self, purpose: &str) -> Result<Nonce>;
+
+    fn consume_nonce(&self, value: &str, purpose: &str) -> Result<bool>;
+
+    fn run_transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()>;
+
+    /* The history and the transparency log, a storage that does not keep them
+       returns Error::NotSupported */
+
+    fn history(&self, public_key: &PublicKey, data_group: &String, data_key: &String,
+               limit: i64, before: Option<i32>) -> Result<(Vec<History>, Option<i32>)>;
+
+    fn log_size(&self) -> Result<i64>;
+
+    fn log_leaves(&self, start: i64, limit: i64) -> Result<Vec<LogLeaf>>;
+
+    fn log_record(&self, public_key: &PublicKey, data_group: &String, data_key: &String) -> Result<Vec<LogLeaf>>;
+
+    fn log_tree(&self) -> Result<Box<dyn Nodes + '_>>;
+}
+
+
+impl dyn Storage {
+    pub fn write_transaction<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        /* All the writes made by f are applied or none of them,
+           and no other write is interleaved with them */
+        let mut f = Some(f);
+        let mut value = None;
+        self.run_transaction(&mut || {
+            value = f.take().map(|f| f()).transpose()?;
+            Ok(())
+        })?;
+        Ok(value.expect("transaction has not been run"))
+    }
+}
+
+
+// The database keeps the history and the transparency log along with the records,
+// the writes are nested into write_transaction of the caller (as savepoints)
+impl Storage for DbConnection {
+    fn check(&self, public_key: &PublicKey) -> Result<bool> {
+        Block::check(self, public_key)
+    }
+
+    fn groups(&self, public_key: &PublicKey) -> Result<Vec<String>> {
+        Block::groups(self, public_key)
+    }
+
+    fn keys(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<String>, Option<String>)> {
+        Block::keys(self, public_key, data_group, page)
+    }
+
+    fn list(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<Block>, Option<String>)> {
+        Block::list(self, public_key, data_group, page)
+    }
+
+    fn meta(&self, public_key: &PublicKey, data_group: &String,
+            page: &Page) -> Result<(Vec<BlockMeta>, Option<String>)> {
+        Block::meta(self, public_key, data_group, page)
+    }
+
+    fn get(&self, public_key: &PublicKey, data_group: &String, data_key: &String) -> Result<Option<Block>> {
+        Block::get(self, public_key, data_group, data_key)
+    }
+
+    fn insert(&self, public_key: &PublicKey, data_group: &String, data_key: &String,
+              data_block: &String, data_encoding: DataEncoding, content_type: &Option<String>,
+              data_version: &String, version_policy: VersionPolicy, signature: &SignatureHex,
+              sig_scheme: SigScheme, secret: &Vec<u8>) -> Result<Block> {
+        self.transaction(|| {
+            Block::insert(self, public_key, data_group, data_key, data_block, data_encoding,
+                          content_type, data_version, version_policy, signature, sig_scheme, secret)?;
+            let new_record = Block::get(self, public_key, data_group, data_key)?.expect("inserted record");
+            LogLeaf::append(self, &new_record, "insert")?;
+            Ok(new_record)
+        })
+    }
+
+    fn update(&self, record: &Block, data_block: &String, data_encoding: DataEncoding,
+              content_type: &Option<String>, data_version: &String, version_policy: VersionPolicy,
+              signature: &SignatureHex, sig_scheme: SigScheme, secret: &Vec<u8>) -> Result<Block> {
+        /* Saving over a tombstone is logged as an insert, the tombstone is archived
+           along with the signature of the deletion */
+        self.transaction(|| {
+            History::archive(self, record, if record.deleted { "revive" } else { "update" })?;
+            Block::update(self, record.id, &record.secret, data_block, data_encoding,
+                          content_type, data_version, version_policy, signature, sig_scheme, secret)?;
+            let public_key = PublicKey::from_hex(&record.public_key)?;
+            let new_record = Block::get(self, &public_key, &record.data_group, &record.data_key)?
+                .expect("updated record");
+            LogLeaf::append(self, &new_record, if record.deleted { "insert" } else { "update" })?;
+            Ok(new_record)
+        })
+    }
+
+    fn delete(&self, record: &Block, delete_signature: &SignatureHex, delete_challenge: &str) -> Result<()> {
+        self.transaction(|| {
+            History::archive(self, record, "delete")?;
+            Block::delete(self, record.id, &record.secret, delete_signature, delete_challenge)?;
+            // The tombstone carries the delete signature into the leaf
+            let public_key = PublicKey::from_hex(&record.public_key)?;
+            let tombstone = Block::get(self, &public_key, &record.data_group, &record.data_key)?
+                .expect("deleted record");
+            LogLeaf::append(self, &tombstone, "delete")?;
+            Ok(())
+        })
+    }
+
+    fn issue_nonce(&self, purpose: &str) -> Result<Nonce> {
+        Nonce::issue(self, purpose)
+    }
+
+    fn consume_nonce(&self, value: &str, purpose: &str) -> Result<bool> {
+        Nonce::consume(self, value, purpose)
+    }
+
+    fn run_transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        db::write_transaction(self, || f())
+    }
+
+    fn history(&self, public_key: &PublicKey, data_group: &String, data_key: &String,
+               limit: i64, before: Option<i32>) -> Result<(Vec<History>, Option<i32>)> {
+        History::list(self, public_key, data_group, data_key, limit, before)
+    }
+
+    fn log_size(&self) -> Result<i64> {
+        LogLeaf::size(self)
+    }
+
+    fn log_leaves(&self, start: i64, limit: i64) -> Result<Vec<LogLeaf>> {
+        LogLeaf::list(self, start, limit)
+    }
+
+    fn log_record(&self, public_key: &PublicKey, data_group: &String, data_key: &String) -> Result<Vec<LogLeaf>> {
+        LogLeaf::of_record(self, public_key, data_group, data_key)
+    }
+
+    fn log_tree(&self) -> Result<Box<dyn Nodes + '_>> {
+        Ok(Box::new(StoredTree(self)))
+    }
+}
+
+
+// The storage of the instance, configured by storage in Rocket.toml
+pub enum Store<'r> {
+    Database(db::Connection),
+    Memory(&'r MemoryStorage),
+}
+
+
+// Managed in both modes, so the request guard never asks for a state that is missing
+pub enum StorageKind {
+    Database,
+    Memory(MemoryStorage),
+}
+
+
+impl<'r> Store<'r> {
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_attach("Storage", |rocket: Rocket| {
+            /* "database" (default) or "memory", the latter keeps nothing between restarts */
+            let result = match rocket.config().get_str("storage") {
+                Ok("memory") => return Ok(rocket.manage(StorageKind::Memory(MemoryStorage::new()))),
+                Ok("database") | Err(ConfigError::Missing(_)) => DbConfig::pool(rocket.config()),
+                Ok(other) => Err(format!("unknown storage {:?}, database or memory is expected", other)),
+                Err(err) => Err(format!("storage: {}", err)),
+            };
+            match result {
+                Ok(pool) => Ok(rocket.manage(pool).manage(StorageKind::Database)),
+                Err(err) => {
+                    eprintln!("Invalid storage configuration: {}", err);
+                    Err(rocket)
+                }
+            }
+        })
+    }
+}
+
+
+impl<'a, 'r> FromRequest<'a, 'r> for Store<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Store<'r>, ()> {
+        let kind = match request.guard::<State<StorageKind>>() {
+            Outcome::Success(kind) => kind.inner(),
+            _ => return Outcome::Failure((Status::ServiceUnavailable, ())),
+        };
+        match kind {
+            StorageKind::Memory(memory) => Outcome::Success(Store::Memory(memory)),
+            StorageKind::Database => request.guard::<db::Connection>().map(Store::Database),
+        }
+    }
+}
+
+
+impl<'r> Deref for Store<'r> {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Store::Database(conn) => &**conn,
+            Store::Memory(memory) => *memory,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use diesel::prelude::*;
+    use crate::utils::hex_to_bytes;
+    use crate::error::Error;
+    use crate::crypto::generate_secret;
+    use crate::page::PageQuery;
+    use crate::merkle;
+    use crate::schema::log_node;
+    use crate::db::testing::TestConnection;
+
+    fn public_key() -> PublicKey {
+        PublicKey::from_hex("ed25519:D75A980182B10AB7D54BFED3C964073A0EE172F3DAA62325AF021A68F707511A").unwrap()
+    }
+
+    fn signature() -> SignatureHex {
+        SignatureHex::try_from("0".repeat(128)).unwrap()
+    }
+
+    fn page(limit: i64, order: &str, cursor: Option<&str>, prefix: Option<&str>) -> Page {
+        PageQuery {
+            limit: Some(limit.to_string()), cursor: cursor.map(str::to_string),
+            order: Some(order.to_string()), prefix: prefix.map(str::to_string), ..PageQuery::default()
+        }.into_page(limit).unwrap()
+    }
+
+    fn insert(storage: &dyn Storage, data_key: &str) -> Block {
+        storage.insert(&public_key(), &"group".to_string(), &data_key.to_string(), &"block".to_string(),
+                       DataEncoding::Utf8, &None, &"1".to_string(), VersionPolicy::None, &signature(),
+                       SigScheme::V1, &generate_secret()).unwrap()
+    }
+
+    fn update(storage: &dyn Storage, record: &Block, data_version: &str) -> Result<Block> {
+        storage.update(record, &"new block".to_string(), DataEncoding::Utf8, &None, &data_version.to_string(),
+                       VersionPolicy::None, &signature(), SigScheme::V2, &generate_secret())
+    }
+
+    #[test]
+    fn test_insert_update_delete() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        let record = insert(storage, "key");
+        assert_eq!(record.data_size, 5);
+        assert!(matches!(
+            storage.insert(&public_key(), &"group".to_string(), &"key".to_string(), &"other".to_string(),
+                           DataEncoding::Utf8, &None, &"1".to_string(), VersionPolicy::None, &signature(),
+                           SigScheme::V1, &generate_secret()),
+            Err(Error::Conflict)
+        ));
+
+        // Compare-and-swap on the secret
+        let updated = update(storage, &record, "2").unwrap();
+        assert_eq!(updated.data_block, "new block");
+        assert_eq!(updated.previous_secret, record.secret);
+        assert!(matches!(update(storage, &record, "3"), Err(Error::Conflict)));
+        assert!(matches!(storage.delete(&record, &signature(), ""), Err(Error::Conflict)));
+
+        storage.delete(&updated, &signature(), "CHALLENGE").unwrap();
+        let tombstone = storage.get(&public_key(), &"group".to_string(), &"key".to_string()).unwrap().unwrap();
+        assert_eq!(tombstone.deleted, true);
+        assert_eq!(tombstone.data_block, "");
+        assert_eq!(tombstone.delete_challenge, "CHALLENGE");
+        assert_eq!(storage.check(&public_key()).unwrap(), false);
+        assert!(matches!(storage.delete(&tombstone, &signature(), ""), Err(Error::Conflict)));
+
+        // Saving over the tombstone brings the record back
+        let revived = update(storage, &tombstone, "3").unwrap();
+        assert_eq!(revived.deleted, false);
+        assert_eq!(revived.delete_signature, "");
+        assert_eq!(storage.check(&public_key()).unwrap(), true);
+    }
+
+    #[test]
+    fn test_pagination() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        for data_key in ["c", "a", "b", "ba"].iter() {
+            insert(storage, data_key);
+        }
+        let group = "group".to_string();
+
+        let (keys, next_cursor) = storage.keys(&public_key(), &group, &page(2, "key", None, None)).unwrap();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(next_cursor, Some("b".to_string()));
+        let (keys, next_cursor) = storage.keys(&public_key(), &group, &page(2, "key", Some("b"), None)).unwrap();
+        assert_eq!(keys, vec!["ba", "c"]);
+        assert_eq!(next_cursor, None);
+        let (keys, _) = storage.keys(&public_key(), &group, &page(10, "key", None, Some("b"))).unwrap();
+        assert_eq!(keys, vec!["b", "ba"]);
+
+        let (records, next_cursor) = storage.list(&public_key(), &group, &page(2, "id", None, None)).unwrap();
+        assert_eq!(records.iter().map(|r| r.data_key.as_str()).collect::<Vec<_>>(), vec!["c", "a"]);
+        assert_eq!(next_cursor, Some(records[1].id.to_string()));
+        let (meta, _) = storage.meta(&public_key(), &group, &page(10, "id", next_cursor.as_deref(), None)).unwrap();
+        assert_eq!(meta.iter().map(|r| r.data_key.as_str()).collect::<Vec<_>>(), vec!["b", "ba"]);
+
+        // Tombstones are listed only if they are asked for
+        let record = storage.get(&public_key(), &group, &"a".to_string()).unwrap().unwrap();
+        storage.delete(&record, &signature(), "").unwrap();
+        let (keys, _) = storage.keys(&public_key(), &group, &page(10, "key", None, None)).unwrap();
+        assert_eq!(keys, vec!["b", "ba", "c"]);
+        let mut with_deleted = page(10, "key", None, None);
+        with_deleted.include_deleted = true;
+        let (keys, _) = storage.keys(&public_key(), &group, &with_deleted).unwrap();
+        assert_eq!(keys, vec!["a", "b", "ba", "c"]);
+        assert_eq!(storage.groups(&public_key()).unwrap(), vec!["group"]);
+    }
+
+    #[test]
+    fn test_log() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        let record = insert(storage, "key");
+        let updated = update(storage, &record, "2").unwrap();
+        storage.delete(&updated, &signature(), "").unwrap();
+        insert(storage, "other");
+        insert(storage, "third");
+
+        assert_eq!(storage.log_size().unwrap(), 5);
+        let leaves = storage.log_leaves(1, 2).unwrap();
+        assert_eq!(leaves.iter().map(|l| l.leaf_index).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(leaves[1].action, "delete");
+        assert_eq!(leaves[1].delete_signature, signature().to_hex());
+        let leaves = storage.log_record(&public_key(), &"group".to_string(), &"key".to_string()).unwrap();
+        assert_eq!(leaves.iter().map(|l| l.action.as_str()).collect::<Vec<_>>(), vec!["insert", "update", "delete"]);
+
+        // The stored nodes give the root of all the leaves
+        let hashes: Vec<Vec<u8>> = storage.log_leaves(0, 10).unwrap().iter()
+                                          .map(|l| hex_to_bytes(&l.leaf_hash)).collect();
+        let tree = storage.log_tree().unwrap();
+        let root = merkle::root(&*tree, 0, 5).unwrap();
+        let expected = merkle::node_hash(
+            &merkle::node_hash(&merkle::node_hash(&hashes[0], &hashes[1]), &merkle::node_hash(&hashes[2], &hashes[3])),
+            &hashes[4]
+        );
+        assert_eq!(root, expected);
+
+        // The nodes of the leaves appended before log_node existed are built again the same
+        diesel::delete(log_node::table).execute(&*conn).unwrap();
+        assert!(merkle::root(&*tree, 0, 5).is_err());
+        assert_eq!(LogLeaf::fill_nodes(&conn).unwrap(), 5);
+        assert_eq!(merkle::root(&*tree, 0, 5).unwrap(), root);
+        assert_eq!(LogLeaf::fill_nodes(&conn).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_nonce() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        let nonce = storage.issue_nonce("insert").unwrap();
+        assert_eq!(storage.consume_nonce(&nonce.value, "auth").unwrap(), false);
+        assert_eq!(storage.consume_nonce(&nonce.value, "insert").unwrap(), true);
+        assert_eq!(storage.consume_nonce(&nonce.value, "insert").unwrap(), false);
+    }
+
+    #[test]
+    fn test_write_transaction() {
+        let conn = match TestConnection::new() { Some(conn) => conn, None => return };
+        let storage: &dyn Storage = &*conn;
+        let result: Result<()> = storage.write_transaction(|| {
+            insert(storage, "key");
+            Err(Error::Conflict)
+        });
+        assert!(result.is_err());
+        assert!(storage.get(&public_key(), &"group".to_string(), &"key".to_string()).unwrap().is_none());
+        assert_eq!(storage.log_size().unwrap(), 0);
+    }
+}
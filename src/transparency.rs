@@ -0,0 +1,222 @@
+use std::sync::Mutex;
+use bigi::Bigi;
+use bigi_ecc::Point;
+use serde_derive::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use diesel;
+use diesel::prelude::*;
+
+use crate::utils::*;
+use crate::db::DbConnection;
+use crate::merkle::{self, Nodes};
+use crate::crypto::sign_hash;
+use crate::keys::PublicKey;
+use crate::error::{Error, Result};
+use crate::block::Block;
+use crate::storage::Storage;
+use crate::schema::{log_leaf, log_node};
+
+pub const MAX_LOG_PAGE_SIZE: i64 = 1000;
+
+// Prefix of the signed tree head hash, so the signature cannot be reused elsewhere
+const TREE_HEAD_TAG: &[u8] = b"hash-storage-tree-head";
+
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct LogLeaf {
+    pub id: i32,
+    pub leaf_index: i64,
+    pub leaf_hash: String,
+    pub action: String,
+    pub public_key: String,
+    pub data_group: String,
+    pub data_key: String,
+    pub data_hash: String,
+    pub data_version: String,
+    pub signature: String,
+    pub created_at: i64,
+    // Set for a delete leaf only
+    pub delete_signature: String,
+    pub delete_challenge: String,
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeHead {
+    pub tree_size: i64,
+    pub timestamp: i64,
+    pub root_hash: String,
+    pub public_key: String,
+    pub signature: String,
+}
+
+
+// The tree of the log in the database, complete subtrees are kept in log_node
+pub struct StoredTree<'a>(pub &'a DbConnection);
+
+
+impl<'a> Nodes for StoredTree<'a> {
+    fn node(&self, level: u32, index: u64) -> Result<Vec<u8>> {
+        let hash: String = match level {
+            0 => log_leaf::table.filter(log_leaf::leaf_index.eq(index as i64))
+                                .select(log_leaf::leaf_hash)
+                                .first(self.0)?,
+            level => log_node::table.filter(log_node::level.eq(level as i32))
+                                    .filter(log_node::node_index.eq(index as i64))
+                                    .select(log_node::node_hash)
+                                    .first(self.0)?,
+        };
+        Ok(hex_to_bytes(&hash))
+    }
+}
+
+
+// The last signed tree head, it is signed again only when the log grows
+#[derive(Default)]
+pub struct TreeHeadCache(Mutex<Option<TreeHead>>);
+
+
+pub fn leaf_data(action: &str, record: &Block) -> Vec<u8> {
+    /* The delete signature of a tombstone is appended, so the log proves the deletion
+       was authorized by the owner. Delete leaves of older versions have none */
+    let mut fields = vec![
+        action.as_bytes(), record.public_key.as_bytes(), record.data_group.as_bytes(),
+        record.data_key.as_bytes(), record.data_hash.as_bytes(),
+        record.data_version.as_bytes(), record.signature.as_bytes(),
+    ];
+    if !record.delete_signature.is_empty() {
+        fields.push(record.delete_signature.as_bytes());
+        fields.push(record.delete_challenge.as_bytes());
+    }
+    length_prefixed(&fields)
+}
+
+
+pub fn hash_tree_head(tree_size: i64, timestamp: i64, root_hash: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(TREE_HEAD_TAG);
+    hasher.input(&(tree_size as u64).to_be_bytes());
+    hasher.input(&timestamp.to_be_bytes());
+    hasher.input(root_hash);
+    hasher.result().to_vec()
+}
+
+
+impl LogLeaf {
+    pub fn compress_public_key(&mut self) {
+        // The leaf hash is built from the stored form, so only the returned key is compressed
+        self.public_key = PublicKey::compress_stored_hex(&self.public_key);
+    }
+
+    pub fn append(conn: &DbConnection, record: &Block, action: &str) -> Result<i64> {
+        /* Must be called inside db::write_transaction, so the index
+           is not taken by a concurrent write */
+        let leaf_index = Self::size(conn)?;
+        let leaf_hash = merkle::leaf_hash(&leaf_data(action, record));
+        diesel::insert_into(log_leaf::table).values((
+            log_leaf::leaf_index.eq(leaf_index),
+            log_leaf::leaf_hash.eq(hex_from_bytes(&leaf_hash)),
+            log_leaf::action.eq(action),
+            log_leaf::public_key.eq(&record.public_key),
+            log_leaf::data_group.eq(&record.data_group),
+            log_leaf::data_key.eq(&record.data_key),
+            log_leaf::data_hash.eq(&record.data_hash),
+            log_leaf::data_version.eq(&record.data_version),
+            log_leaf::signature.eq(&record.signature),
+            log_leaf::created_at.eq(timestamp()),
+            log_leaf::delete_signature.eq(&record.delete_signature),
+            log_leaf::delete_challenge.eq(&record.delete_challenge),
+        )).execute(conn)?;
+        Self::append_nodes(conn, leaf_index, &leaf_hash)?;
+        Ok(leaf_index)
+    }
+
+    fn append_nodes(conn: &DbConnection, leaf_index: i64, leaf_hash: &[u8]) -> Result<()> {
+        for (level, index, hash) in merkle::append(&StoredTree(conn), leaf_index as u64, leaf_hash)? {
+            diesel::insert_into(log_node::table).values((
+                log_node::level.eq(level as i32),
+                log_node::node_index.eq(index as i64),
+                log_node::node_hash.eq(hex_from_bytes(&hash)),
+            )).execute(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn fill_nodes(conn: &DbConnection) -> Result<i64> {
+        /* Builds the nodes of the leaves appended before log_node was added.
+           The nodes are complete up to an even leaf, the first missing one is the pair
+           of the leaves after it, so the leaves are appended again from there */
+        let pairs: i64 = log_node::table.filter(log_node::level.eq(1)).count().get_result(conn)?;
+        let size = Self::size(conn)?;
+        for leaf_index in (2 * pairs)..size {
+            let leaf_hash = StoredTree(conn).node(0, leaf_index as u64)?;
+            Self::append_nodes(conn, leaf_index, &leaf_hash)?;
+        }
+        Ok((size - 2 * pairs).max(0))
+    }
+
+    pub fn size(conn: &DbConnection) -> Result<i64> {
+        // The indices have no gaps, the maximum is taken from the unique index
+        let last: Option<i64> = log_leaf::table.select(diesel::dsl::max(log_leaf::leaf_index)).first(conn)?;
+        Ok(last.map_or(0, |last| last + 1))
+    }
+
+    pub fn list(conn: &DbConnection, start: i64, limit: i64) -> Result<Vec<Self>> {
+        Ok(log_leaf::table.filter(log_leaf::leaf_index.ge(start))
+                          .order(log_leaf::leaf_index.asc())
+                          .limit(limit).load(conn)?)
+    }
+
+    pub fn of_record(conn: &DbConnection, public_key: &PublicKey,
+                     data_group: &String, data_key: &String) -> Result<Vec<Self>> {
+        Ok(log_leaf::table.filter(log_leaf::public_key.eq(public_key.to_hex()))
+                          .filter(log_leaf::data_group.eq(data_group))
+                          .filter(log_leaf::data_key.eq(data_key))
+                          .order(log_leaf::leaf_index.asc())
+                          .load(conn)?)
+    }
+
+    pub fn checked_size(size: i64, tree_size: Option<i64>) -> Result<i64> {
+        /* The current size if it is not given, otherwise it must not exceed the current size */
+        match tree_size {
+            None => Ok(size),
+            Some(tree_size) if tree_size < 0 || tree_size > size => Err(Error::BadQuery(
+                format!("tree_size must be between 0 and {}", size)
+            )),
+            Some(tree_size) => Ok(tree_size),
+        }
+    }
+}
+
+
+impl TreeHead {
+    pub fn build(nodes: &dyn Nodes, tree_size: i64, private_key: &Bigi, public_key: &Point) -> Result<Self> {
+        let timestamp = timestamp();
+        let root_hash = merkle::root(nodes, 0, tree_size as u64)?;
+        let signature = sign_hash(private_key, &hash_tree_head(tree_size, timestamp, &root_hash));
+        Ok(TreeHead {
+            tree_size,
+            timestamp,
+            root_hash: hex_from_bytes(&root_hash),
+            public_key: hex_from_point(public_key),
+            signature: hex_from_bigi_pair(&signature),
+        })
+    }
+}
+
+
+impl TreeHeadCache {
+    pub fn get(&self, storage: &dyn Storage, private_key: &Bigi, public_key: &Point) -> Result<TreeHead> {
+        /* The lock is held while the head is built, so concurrent requests sign it once */
+        let tree_size = storage.log_size()?;
+        let mut cached = self.0.lock().unwrap();
+        match &*cached {
+            Some(head) if head.tree_size == tree_size => Ok(head.clone()),
+            _ => {
+                let head = TreeHead::build(&*storage.log_tree()?, tree_size, private_key, public_key)?;
+                *cached = Some(head.clone());
+                Ok(head)
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::convert::TryFrom;
+use bigi::Bigi;
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+
+use crate::HASH_STORAGE_BITS;
+use crate::utils::*;
+use crate::keys::PublicKey;
+use crate::error::{Error, Result};
+
+
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+pub struct PublicKeyHex(PublicKey);
+
+
+// The pair is used by Secp256k1, the raw bytes r||s (or R||S of Ed25519) by the other key types
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SignatureHex {
+    pair: (Bigi, Bigi),
+    bytes: Vec<u8>,
+}
+
+
+impl PublicKeyHex {
+    pub fn key(&self) -> &PublicKey {
+        &self.0
+    }
+
+    pub fn into_key(self) -> PublicKey {
+        self.0
+    }
+}
+
+
+impl SignatureHex {
+    pub fn from_pair(pair: (Bigi, Bigi)) -> Self {
+        let bytes = hex_to_bytes(&hex_from_bigi_pair(&pair));
+        SignatureHex { pair, bytes }
+    }
+
+    pub fn from_be_bytes(bytes: Vec<u8>) -> Self {
+        /* r||s in big-endian as DER and the key types other than Secp256k1 have */
+        let size = HASH_STORAGE_BITS / 8;
+        let pair = (bigi_from_be_bytes(&bytes[..size]), bigi_from_be_bytes(&bytes[size..]));
+        SignatureHex { pair, bytes }
+    }
+
+    pub fn pair(&self) -> &(Bigi, Bigi) {
+        &self.pair
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex_from_bytes(&self.bytes)
+    }
+}
+
+
+impl TryFrom<String> for PublicKeyHex {
+    type Error = Error;
+
+    fn try_from(hex: String) -> Result<Self> {
+        PublicKey::from_hex(&hex).map(PublicKeyHex)
+    }
+}
+
+
+impl TryFrom<String> for SignatureHex {
+    type Error = Error;
+
+    fn try_from(hex: String) -> Result<Self> {
+        /* The raw form is exactly r||s, any other length must be a strict DER encoding.
+           A DER encoding of 64 bytes would need r and s of 29 bytes both, ECDSA does not
+           produce such short values in practice, so the forms are told apart by the length */
+        if hex.len() == HASH_STORAGE_BITS / 2 {
+            let pair = try_hex_to_bigi_pair(&hex)?;
+            Ok(SignatureHex { pair, bytes: hex_to_bytes(&hex) })
+        } else {
+            let (r, s) = try_der_to_signature(&hex)?;
+            Ok(SignatureHex::from_be_bytes([r, s].concat()))
+        }
+    }
+}
+
+
+impl<'a> FromParam<'a> for PublicKeyHex {
+    type Error = Error;
+
+    fn from_param(param: &'a RawStr) -> Result<Self> {
+        PublicKeyHex::try_from(param.as_str().to_string())
+    }
+}
+
+
+impl<'a> FromParam<'a> for SignatureHex {
+    type Error = Error;
+
+    fn from_param(param: &'a RawStr) -> Result<Self> {
+        SignatureHex::try_from(param.as_str().to_string())
+    }
+}
+
+
+impl Serialize for PublicKeyHex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_hex())
+    }
+}
+
+
+impl Serialize for SignatureHex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+
+pub fn deserialize_optional_signature<'de, D>(deserializer: D) -> std::result::Result<Option<SignatureHex>, D::Error>
+        where D: Deserializer<'de> {
+    // Both null and an empty string mean the signature is not given
+    match Option::<String>::deserialize(deserializer)? {
+        Some(hex) if !hex.is_empty() => SignatureHex::try_from(hex).map(Some).map_err(serde::de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Input {
+        #[serde(default, deserialize_with = "deserialize_optional_signature")]
+        signature: Option<SignatureHex>,
+    }
+
+    fn parse(input: &str) -> std::result::Result<Option<SignatureHex>, serde_json::Error> {
+        serde_json::from_str::<Input>(input).map(|input| input.signature)
+    }
+
+    #[test]
+    fn test_deserialize_optional_signature() {
+        assert!(parse(r#"{}"#).unwrap().is_none());
+        assert!(parse(r#"{"signature": null}"#).unwrap().is_none());
+        assert!(parse(r#"{"signature": ""}"#).unwrap().is_none());
+        let hex = "1F".repeat(64);
+        let signature = parse(&format!(r#"{{"signature": "{}"}}"#, hex)).unwrap().unwrap();
+        assert_eq!(signature.to_hex(), hex);
+        assert!(parse(r#"{"signature": "XYZ"}"#).is_err());
+        assert!(parse(r#"{"signature": 5}"#).is_err());
+    }
+}
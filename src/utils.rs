@@ -1,7 +1,10 @@
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 use bigi::Bigi;
 use bigi_ecc::{point, Point};
 
 use crate::HASH_STORAGE_BITS;
+use crate::error::{Error, Result};
 
 const BIGI_HEX_LENGTH: usize = HASH_STORAGE_BITS / 4;
 
@@ -28,6 +31,21 @@ pub fn hex_from_bigi(b: &Bigi) -> String {
 }
 
 
+pub fn bigi_to_be_bytes(b: &Bigi) -> Vec<u8> {
+    /* Bigi keeps bytes in little-endian order, this returns the big-endian ones */
+    let mut bytes = b.to_bytes()[..(HASH_STORAGE_BITS / 8)].to_vec();
+    bytes.reverse();
+    bytes
+}
+
+
+pub fn bigi_from_be_bytes(bytes: &[u8]) -> Bigi {
+    let mut bytes = bytes.to_vec();
+    bytes.reverse();
+    Bigi::from_bytes(&bytes)
+}
+
+
 pub fn hex_to_point(hex: &str) -> Point {
     point!(
         hex_to_bigi(&hex[..BIGI_HEX_LENGTH]),
@@ -41,6 +59,17 @@ pub fn hex_from_point(p: &Point) -> String {
 }
 
 
+pub fn hex_from_point_compressed(p: &Point) -> String {
+    /* SEC1 compressed form: parity prefix and big-endian x */
+    let prefix = if bigi_to_be_bytes(&p.y)[HASH_STORAGE_BITS / 8 - 1] & 1 == 1 {
+        "03"
+    } else {
+        "02"
+    };
+    prefix.to_string() + &hex_from_bytes(&bigi_to_be_bytes(&p.x))
+}
+
+
 pub fn hex_to_bigi_vec(hex: &str) -> Vec<Bigi> {
     (0..hex.len()).step_by(BIGI_HEX_LENGTH).map(
         |i| hex_to_bigi(&hex[i..(i + BIGI_HEX_LENGTH)])
@@ -78,6 +107,180 @@ pub fn hex_from_bigi_pair(b: &(Bigi, Bigi)) -> String {
 }
 
 
+pub fn timestamp() -> i64 {
+    /* Current Unix time in seconds */
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+
+pub fn length_prefixed(fields: &[&[u8]]) -> Vec<u8> {
+    /* Concatenates the fields each prefixed by its length (4 bytes big-endian),
+       so different sets of fields cannot produce the same bytes */
+    let mut data = Vec::new();
+    for field in fields.iter() {
+        data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        data.extend_from_slice(field);
+    }
+    data
+}
+
+
+pub fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    /* The smallest string greater than all strings starting with prefix,
+       None if there is no such one (prefix consists of char::MAX only) */
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(c) = chars.pop() {
+        let next = (c as u32 + 1..=char::MAX as u32).find_map(std::char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+
+pub fn check_hex(hex: &str, length: Option<usize>) -> Result<()> {
+    /* Hex values must have an even length and consist of 0-9 and A-F (in any case),
+       they are stored in upper case */
+    if let Some(length) = length {
+        if hex.len() != length {
+            return Err(Error::BadHex(
+                format!("expected {} characters, got {}", length, hex.len())
+            ));
+        }
+    }
+    if hex.len() % 2 != 0 {
+        return Err(Error::BadHex("odd number of characters".to_string()));
+    }
+    match hex.chars().find(|c| !c.is_ascii_hexdigit()) {
+        Some(c) => Err(Error::BadHex(format!("unexpected character {:?}", c))),
+        None => Ok(())
+    }
+}
+
+
+pub fn try_hex_to_bytes(hex: &str) -> Result<Vec<u8>> {
+    check_hex(hex, None)?;
+    Ok(hex_to_bytes(hex))
+}
+
+
+pub fn try_hex_to_bigi(hex: &str) -> Result<Bigi> {
+    check_hex(hex, Some(BIGI_HEX_LENGTH))?;
+    Ok(hex_to_bigi(hex))
+}
+
+
+pub fn try_hex_to_point(hex: &str) -> Result<Point> {
+    check_hex(hex, Some(2 * BIGI_HEX_LENGTH))?;
+    Ok(hex_to_point(hex))
+}
+
+
+pub fn try_hex_to_point_compressed(hex: &str) -> Result<(bool, Bigi)> {
+    /* Returns the parity of y and x of a SEC1 compressed point */
+    check_hex(hex, Some(BIGI_HEX_LENGTH + 2))?;
+    let is_odd = match &hex[..2] {
+        "02" => false,
+        "03" => true,
+        _ => return Err(Error::BadHex("compressed key must start with 02 or 03".to_string())),
+    };
+    Ok((is_odd, bigi_from_be_bytes(&hex_to_bytes(&hex[2..]))))
+}
+
+
+pub fn try_hex_to_bigi_pair(hex: &str) -> Result<(Bigi, Bigi)> {
+    check_hex(hex, Some(2 * BIGI_HEX_LENGTH))?;
+    Ok(hex_to_bigi_pair(hex))
+}
+
+
+pub fn try_der_to_signature(hex: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    /* Parses a DER-encoded ECDSA signature (SEQUENCE of two INTEGERs),
+       returns r and s as big-endian bytes of the fixed length.
+       Only the minimal encoding is accepted, so a signature has a single DER form */
+    check_hex(hex, None)?;
+    let bytes = hex_to_bytes(hex);
+    let size = HASH_STORAGE_BITS / 8;
+    let bad_der = || Error::BadHex("malformed DER signature".to_string());
+
+    // The short form of the lengths only, the signatures are shorter than 128 bytes
+    if bytes.len() < 2 || bytes[0] != 0x30 || bytes[1] >= 0x80 || bytes[1] as usize != bytes.len() - 2 {
+        return Err(bad_der());
+    }
+    let mut rest = &bytes[2..];
+    let mut integers = Vec::with_capacity(2);
+    for _ in 0..2 {
+        if rest.len() < 2 || rest[0] != 0x02 {
+            return Err(bad_der());
+        }
+        let length = rest[1] as usize;
+        if length == 0 || length >= 0x80 || rest.len() < 2 + length {
+            return Err(bad_der());
+        }
+        let mut value = &rest[2..(2 + length)];
+        if value[0] & 0x80 != 0 {
+            // Negative numbers are not valid signatures
+            return Err(bad_der());
+        }
+        if value.len() > 1 && value[0] == 0 {
+            // A leading zero is allowed only to keep the next byte from being the sign
+            if value[1] & 0x80 == 0 {
+                return Err(bad_der());
+            }
+            value = &value[1..];
+        }
+        if value.len() > size {
+            return Err(bad_der());
+        }
+        let mut integer = vec![0u8; size - value.len()];
+        integer.extend_from_slice(value);
+        integers.push(integer);
+        rest = &rest[(2 + length)..];
+    }
+    if !rest.is_empty() {
+        return Err(bad_der());
+    }
+    let s = integers.pop().unwrap();
+    let r = integers.pop().unwrap();
+    Ok((r, s))
+}
+
+
+pub fn be_sub(a: &[u8], b: &[u8]) -> Vec<u8> {
+    /* a - b for big-endian numbers of the same length, a must not be less than b */
+    let mut result = vec![0u8; a.len()];
+    let mut borrow = 0i16;
+    for i in (0..a.len()).rev() {
+        let mut diff = a[i] as i16 - b[i] as i16 - borrow;
+        borrow = if diff < 0 { 1 } else { 0 };
+        if diff < 0 {
+            diff += 256;
+        }
+        result[i] = diff as u8;
+    }
+    result
+}
+
+
+pub fn par_map<T: Sync, R: Send, F: Fn(&T) -> R + Sync>(items: &[T], f: F) -> Vec<R> {
+    /* Maps the items in parallel keeping the order, one chunk per available CPU */
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    if threads < 2 || items.len() < 2 {
+        return items.iter().map(f).collect();
+    }
+    let chunk_size = (items.len() + threads - 1) / threads;
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = items.chunks(chunk_size).map(|chunk| {
+            scope.spawn(move || chunk.iter().map(f).collect::<Vec<R>>())
+        }).collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,6 +298,85 @@ mod tests {
         assert_eq!(hex_to_bytes(&"7B0C43FF".to_string()), vec![123, 12, 67, 255]);
     }
 
+    #[test]
+    fn test_check_hex() {
+        assert!(check_hex("", None).is_ok());
+        assert!(check_hex("7B0C43FF", None).is_ok());
+        assert!(check_hex("7B0C43FF", Some(8)).is_ok());
+        assert!(check_hex("7B0C43FF", Some(64)).is_err());
+        assert!(check_hex("7B0C43F", None).is_err());
+        assert!(check_hex("7b0c43ff", None).is_ok());
+        assert!(check_hex("7B0C43FG", None).is_err());
+        assert!(check_hex("7B0C43\u{e9}", None).is_err());
+    }
+
+    #[test]
+    fn test_try_hex_to_point() {
+        let hex = "604CE6D82472A1D921BE694155A2C76E02F33330E6CD9045B5AD4A6BD6778F657560BCAD1C18397063E46155EC684151A59E1AAE0AA4F43DBB09525C0DD768ED";
+        assert_eq!(hex_from_point(&try_hex_to_point(hex).unwrap()), hex);
+        assert!(try_hex_to_point(&hex[..64]).is_err());
+        assert!(try_hex_to_bigi_pair(&hex[1..]).is_err());
+        assert_eq!(hex_from_point(&try_hex_to_point(&hex.to_lowercase()).unwrap()), hex);
+    }
+
+    #[test]
+    fn test_hex_to_bytes_lowercase() {
+        assert_eq!(try_hex_to_bytes("7b0c43ff").unwrap(), vec![123, 12, 67, 255]);
+        assert_eq!(hex_from_bytes(&try_hex_to_bytes("7b0C43fF").unwrap()), "7B0C43FF");
+    }
+
+    #[test]
+    fn test_prefix_upper_bound() {
+        assert_eq!(prefix_upper_bound("2026-10"), Some("2026-11".to_string()));
+        assert_eq!(prefix_upper_bound("a\u{d7ff}"), Some("a\u{e000}".to_string()));
+        assert_eq!(prefix_upper_bound("a\u{10ffff}"), Some("b".to_string()));
+        assert_eq!(prefix_upper_bound("\u{10ffff}"), None);
+        assert_eq!(prefix_upper_bound(""), None);
+    }
+
+    #[test]
+    fn test_try_der_to_signature() {
+        let (r, s) = try_der_to_signature("3007020101020200FF").unwrap();
+        assert_eq!(r, [vec![0u8; 31], vec![1]].concat());
+        assert_eq!(s, [vec![0u8; 31], vec![255]].concat());
+        assert!(try_der_to_signature("3006020101020180").is_err());
+        assert!(try_der_to_signature("3006020101020201").is_err());
+        assert!(try_der_to_signature("3008020101020200FF00").is_err());
+        // Non-minimal integers and lengths
+        assert!(try_der_to_signature("300702010102020001").is_err());
+        assert!(try_der_to_signature("30080202000102020080").is_err());
+        assert!(try_der_to_signature("308106020101020101").is_err());
+        assert!(try_der_to_signature("30070201010281010F").is_err());
+        assert!(try_der_to_signature("3006020101020100").is_ok());
+    }
+
+    #[test]
+    fn test_be_sub() {
+        assert_eq!(be_sub(&[1, 0], &[0, 1]), vec![0, 255]);
+        assert_eq!(be_sub(&[5, 7], &[5, 7]), vec![0, 0]);
+        assert_eq!(be_sub(&[255, 255], &[0, 1]), vec![255, 254]);
+    }
+
+    #[test]
+    fn test_length_prefixed() {
+        assert_eq!(length_prefixed(&[b"ab", b"c"]), vec![0, 0, 0, 2, 97, 98, 0, 0, 0, 1, 99]);
+        assert_ne!(length_prefixed(&[b"ab", b"c"]), length_prefixed(&[b"a", b"bc"]));
+        assert_eq!(length_prefixed(&[b""]), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_hex_point_compressed() {
+        let hex = "604CE6D82472A1D921BE694155A2C76E02F33330E6CD9045B5AD4A6BD6778F657560BCAD1C18397063E46155EC684151A59E1AAE0AA4F43DBB09525C0DD768ED";
+        let p = hex_to_point(hex);
+        let compressed = hex_from_point_compressed(&p);
+        assert_eq!(compressed.len(), 66);
+        assert_eq!(&compressed[2..], hex_from_bytes(&bigi_to_be_bytes(&p.x)));
+        let (is_odd, x) = try_hex_to_point_compressed(&compressed).unwrap();
+        assert_eq!(is_odd, &compressed[..2] == "03");
+        assert_eq!(hex_from_bigi(&x), hex_from_bigi(&p.x));
+        assert!(try_hex_to_point_compressed(&("04".to_string() + &compressed[2..])).is_err());
+    }
+
     #[bench]
     fn bench_hex_from_bytes(b: &mut Bencher) {
         let bytes: Vec<u8> = (0..256).map(|_| { rand::random::<u8>() }).collect();
@@ -172,4 +454,11 @@ mod tests {
         let hex = "604CE6D82472A1D921BE694155A2C76E02F33330E6CD9045B5AD4A6BD6778F657560BCAD1C18397063E46155EC684151A59E1AAE0AA4F43DBB09525C0DD768ED";
         b.iter(|| hex_to_point_vec(&hex));
     }
+
+    #[test]
+    fn test_par_map() {
+        let items: Vec<u32> = (0..1000).collect();
+        assert_eq!(par_map(&items, |x| x * 2), items.iter().map(|x| x * 2).collect::<Vec<u32>>());
+        assert_eq!(par_map(&Vec::<u32>::new(), |x| x * 2), Vec::<u32>::new());
+    }
 }
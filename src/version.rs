@@ -0,0 +1,103 @@
+use std::str::FromStr;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::error::{Error, Result};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionPolicy {
+    None,
+    Integer,
+    Semver,
+}
+
+
+impl FromStr for VersionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(VersionPolicy::None),
+            "integer" => Ok(VersionPolicy::Integer),
+            "semver" => Ok(VersionPolicy::Semver),
+            _ => Err(format!("unknown version policy {:?}", s)),
+        }
+    }
+}
+
+
+impl VersionPolicy {
+    pub fn parse(&self, version: &str) -> Result<Vec<u64>> {
+        /* Converts a version into numbers that are compared lexicographically */
+        let parts: Vec<&str> = match self {
+            VersionPolicy::None => return Ok(vec![]),
+            VersionPolicy::Integer => vec![version],
+            VersionPolicy::Semver => version.split('+').next().unwrap_or("").split('.').collect(),
+        };
+        if *self == VersionPolicy::Semver && parts.len() != 3 {
+            return Err(Error::BadVersion(format!("{:?} is not MAJOR.MINOR.PATCH", version)));
+        }
+        parts.iter().map(|part| {
+            // u64::from_str accepts a leading plus that is not allowed here
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Error::BadVersion(format!("{:?} is not a {} version", version, self.name())));
+            }
+            part.parse().map_err(|_| Error::BadVersion(format!("{:?} is too large", version)))
+        }).collect()
+    }
+
+    pub fn check_newer(&self, new_version: &str, old_version: &str) -> Result<()> {
+        /* Passes if new_version is strictly greater than old_version.
+           A stored version that does not follow the policy (saved before
+           the policy was applied) is considered the lowest one. */
+        if *self == VersionPolicy::None {
+            return Ok(());
+        }
+        let new = self.parse(new_version)?;
+        let old = self.parse(old_version).unwrap_or_default();
+        if new > old {
+            Ok(())
+        } else {
+            Err(Error::StaleVersion)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VersionPolicy::None => "none",
+            VersionPolicy::Integer => "integer",
+            VersionPolicy::Semver => "semver",
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(VersionPolicy::Integer.parse("25").unwrap(), vec![25]);
+        assert!(VersionPolicy::Integer.parse("").is_err());
+        assert!(VersionPolicy::Integer.parse("+5").is_err());
+        assert!(VersionPolicy::Integer.parse("1.2").is_err());
+        assert_eq!(VersionPolicy::Semver.parse("1.12.3").unwrap(), vec![1, 12, 3]);
+        assert_eq!(VersionPolicy::Semver.parse("1.12.3+build5").unwrap(), vec![1, 12, 3]);
+        assert!(VersionPolicy::Semver.parse("1.12").is_err());
+        assert!(VersionPolicy::Semver.parse("1.12.3-beta").is_err());
+    }
+
+    #[test]
+    fn test_check_newer() {
+        assert!(VersionPolicy::None.check_newer("1", "2").is_ok());
+        assert!(VersionPolicy::Integer.check_newer("10", "9").is_ok());
+        assert!(VersionPolicy::Integer.check_newer("9", "9").is_err());
+        assert!(VersionPolicy::Integer.check_newer("8", "9").is_err());
+        assert!(VersionPolicy::Integer.check_newer("10", "").is_ok());
+        assert!(VersionPolicy::Integer.check_newer("", "10").is_err());
+        assert!(VersionPolicy::Semver.check_newer("1.10.0", "1.9.7").is_ok());
+        assert!(VersionPolicy::Semver.check_newer("1.9.7", "1.10.0").is_err());
+    }
+}